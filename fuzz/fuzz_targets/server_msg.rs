@@ -0,0 +1,11 @@
+//! Decoding of requests received by the elevated client
+//!
+//! Run on Linux with `cargo +nightly fuzz run server_msg`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    winusb_installer::fuzzing::decode_server_msgs(data);
+});
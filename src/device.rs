@@ -0,0 +1,617 @@
+//! Platform independent description of devices, drivers and installation configuration
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::num::{NonZeroU64, NonZeroU8};
+
+use serde::{Serialize, Deserialize};
+
+pub type DeviceFilter = dyn Fn(&Device) -> bool + Send;
+
+/// Device information. On Windows this is an owned version of `libwdi::DeviceInfo`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Device {
+    pub vid: u16,
+    pub pid: u16,
+    pub is_composite: bool,
+    pub mi: Option<NonZeroU8>,
+    pub driver_version: Option<NonZeroU64>,
+    pub desc: String,
+    pub driver: Option<String>,
+    pub device_id: Option<String>,
+    pub hardware_id: Option<String>,
+    pub compatible_id: Option<String>,
+    pub upper_filter: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallConfig {
+    /// Name that will be visible as the "Manufacturer" device property in device manager
+    pub vendor: String,
+    /// The directory where the .inf and driver files should be crated, e.g.
+    /// `C:\Program Files\MyApp\driver`
+    ///
+    /// The client writes there with admin privileges and then installs the files, so it must
+    /// not be writable by regular users (see [`InstallConfig::validate`]). If empty,
+    /// [`InstallConfig::default_driver_path`] is used. Not used on Linux.
+    pub driver_path: String,
+    /// The name of the .inf file to generate (includeing the .inf extension)
+    ///
+    /// On Linux it determines the name of generated udev rules file, e.g. `MyWinUSB.inf`
+    /// results in `/etc/udev/rules.d/70-MyWinUSB.rules`.
+    pub inf_name: String,
+}
+
+/// Problem with [`InstallConfig`] found by [`InstallConfig::validate`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConfigError {
+    /// Vendor name is empty
+    EmptyVendor,
+    /// Vendor name contains control characters (including NUL)
+    InvalidVendor(String),
+    /// Name of the .inf file contains path separators or characters not allowed in file names
+    InfNameNotFileName(String),
+    /// Name of the .inf file does not have the `.inf` extension or is only the extension
+    InfNameExtension(String),
+    /// Driver path is not an absolute path with a drive letter
+    RelativeDriverPath(String),
+    /// Driver path is a network (UNC) path
+    NetworkDriverPath(String),
+    /// Driver path contains `..`
+    DriverPathTraversal(String),
+    /// Driver path is not inside the `Program Files` directory
+    UnprotectedDriverPath(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyVendor => write!(f, "Vendor name is empty"),
+            Self::InvalidVendor(vendor) => write!(f, "Vendor name {:?} contains control characters", vendor),
+            Self::InfNameNotFileName(name) => write!(f, "Inf name {:?} is not a valid file name", name),
+            Self::InfNameExtension(name) => write!(f, "Inf name {:?} must have the .inf extension", name),
+            Self::RelativeDriverPath(path) => write!(f, "Driver path {:?} is not absolute", path),
+            Self::NetworkDriverPath(path) => write!(f, "Driver path {:?} is a network path", path),
+            Self::DriverPathTraversal(path) => write!(f, "Driver path {:?} contains '..'", path),
+            Self::UnprotectedDriverPath(path) => {
+                write!(f, "Driver path {:?} is not in a directory protected from regular users", path)
+            },
+        }
+    }
+}
+
+impl InstallConfig {
+    /// Configuration with [`Self::default_driver_path`]
+    pub fn new(vendor: impl Into<String>, inf_name: impl Into<String>) -> Self {
+        Self {
+            vendor: vendor.into(),
+            driver_path: String::new(),
+            inf_name: inf_name.into(),
+        }
+    }
+
+    /// Default directory for driver files of given vendor
+    ///
+    /// On Windows it is `<Program Files>\winusb-installer\<vendor>`, which only administrators
+    /// can write to.
+    pub fn default_driver_path(vendor: &str) -> String {
+        let vendor: String = vendor.trim().chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        if cfg!(windows) {
+            let program_files = protected_dirs().into_iter().next()
+                .unwrap_or_else(|| r"C:\Program Files".to_string());
+            format!(r"{}\winusb-installer\{}", program_files.trim_end_matches('\\'), vendor)
+        } else {
+            format!("/var/lib/winusb-installer/{}", vendor)
+        }
+    }
+
+    /// Canonical form of the configuration
+    ///
+    /// Trims whitespace, fills the default driver path if empty and on Windows normalizes
+    /// separators of the driver path. Does not validate anything.
+    pub fn canonical(&self) -> Self {
+        let vendor = self.vendor.trim().to_string();
+        let driver_path = match self.driver_path.trim() {
+            "" => Self::default_driver_path(&vendor),
+            path if cfg!(windows) => normalize_windows_path(path),
+            path => path.to_string(),
+        };
+        Self {
+            vendor,
+            driver_path,
+            inf_name: self.inf_name.trim().to_string(),
+        }
+    }
+
+    /// Check that the configuration is safe to be used by the elevated client
+    ///
+    /// Should be called on the [`Self::canonical`] form. Both [`crate::Server::install`] and
+    /// the client do that. The driver path is only checked on Windows, where it must be inside
+    /// the `Program Files` directory, because a directory writable by the user would allow
+    /// replacing the driver files before the installation.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.vendor.trim().is_empty() {
+            return Err(ConfigError::EmptyVendor);
+        }
+        if self.vendor.chars().any(char::is_control) {
+            return Err(ConfigError::InvalidVendor(self.vendor.clone()));
+        }
+
+        let name = &self.inf_name;
+        let reserved = |c: char| c.is_control() || r#"<>:"/\|?*"#.contains(c);
+        if name.is_empty() || name.chars().any(reserved) || name.trim_end_matches('.').is_empty() {
+            return Err(ConfigError::InfNameNotFileName(name.clone()));
+        }
+        let stem = name.len().checked_sub(".inf".len())
+            .filter(|&len| len > 0 && name.is_char_boundary(len) && name[len..].eq_ignore_ascii_case(".inf"));
+        if stem.is_none() {
+            return Err(ConfigError::InfNameExtension(name.clone()));
+        }
+
+        if cfg!(windows) {
+            check_windows_driver_path(&self.driver_path, &protected_dirs())?;
+        }
+        Ok(())
+    }
+}
+
+/// Directories only writable by administrators, in which the driver path must be
+#[cfg(windows)]
+fn protected_dirs() -> Vec<String> {
+    crate::winusb::program_files_dirs()
+}
+
+#[cfg(not(windows))]
+fn protected_dirs() -> Vec<String> {
+    Vec::new()
+}
+
+/// Use backslashes, remove duplicate separators, `.` components and trailing separators
+fn normalize_windows_path(path: &str) -> String {
+    let unc = is_unc(path);
+    let components: Vec<_> = path.split(['\\', '/'])
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    let mut normalized = components.join(r"\");
+    if unc {
+        normalized.insert_str(0, r"\\");
+    } else if components.len() == 1 && normalized.ends_with(':') {
+        // Root of a drive
+        normalized.push('\\');
+    }
+    normalized
+}
+
+/// Network or device path, starting with two separators of any kind (including `\\?\`)
+fn is_unc(path: &str) -> bool {
+    let mut chars = path.chars();
+    chars.next().is_some_and(|c| c == '\\' || c == '/') && chars.next().is_some_and(|c| c == '\\' || c == '/')
+}
+
+fn check_windows_driver_path(path: &str, protected: &[String]) -> Result<(), ConfigError> {
+    if is_unc(path) {
+        return Err(ConfigError::NetworkDriverPath(path.to_string()));
+    }
+    let bytes = path.as_bytes();
+    let has_drive = bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+        && (bytes[2] == b'\\' || bytes[2] == b'/');
+    if !has_drive {
+        return Err(ConfigError::RelativeDriverPath(path.to_string()));
+    }
+    if path.split(['\\', '/']).any(|component| component == "..") {
+        return Err(ConfigError::DriverPathTraversal(path.to_string()));
+    }
+    // Paths are case-insensitive, the directory itself must be below the protected root
+    let normalized = normalize_windows_path(path).to_lowercase();
+    let inside = protected.iter()
+        .map(|root| normalize_windows_path(root).to_lowercase())
+        .any(|root| normalized.strip_prefix(&root).is_some_and(|rest| rest.starts_with('\\') && rest.len() > 1));
+    if !inside {
+        return Err(ConfigError::UnprotectedDriverPath(path.to_string()));
+    }
+    Ok(())
+}
+
+impl Device {
+    /// Convenience method for checking if device has WinUSB driver installed
+    pub fn has_winusb(&self) -> bool {
+        self.driver.as_ref().is_some_and(|driver| driver.to_lowercase() == "winusb")
+    }
+
+    /// Identity of the device that does not change when a driver is installed
+    pub fn key(&self) -> DeviceKey {
+        DeviceKey {
+            vid: self.vid,
+            pid: self.pid,
+            mi: self.mi,
+            device_id: self.device_id.clone(),
+        }
+    }
+
+    /// List fields (other than the ones in [`DeviceKey`]) that differ between the devices
+    pub fn changed_fields(&self, other: &Device) -> Vec<DeviceField> {
+        let mut fields = Vec::new();
+        let mut check = |changed: bool, field| {
+            if changed {
+                fields.push(field);
+            }
+        };
+        check(self.is_composite != other.is_composite, DeviceField::IsComposite);
+        check(self.driver_version != other.driver_version, DeviceField::DriverVersion);
+        check(self.desc != other.desc, DeviceField::Desc);
+        check(self.driver != other.driver, DeviceField::Driver);
+        check(self.hardware_id != other.hardware_id, DeviceField::HardwareId);
+        check(self.compatible_id != other.compatible_id, DeviceField::CompatibleId);
+        check(self.upper_filter != other.upper_filter, DeviceField::UpperFilter);
+        fields
+    }
+}
+
+/// Identity of a device, see [`Device::key`]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct DeviceKey {
+    pub vid: u16,
+    pub pid: u16,
+    pub mi: Option<NonZeroU8>,
+    pub device_id: Option<String>,
+}
+
+/// [`Device`] fields that can change without changing the device identity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DeviceField {
+    IsComposite,
+    DriverVersion,
+    Desc,
+    Driver,
+    HardwareId,
+    CompatibleId,
+    UpperFilter,
+}
+
+/// List of devices present at some point in time, e.g. the result of
+/// [`crate::Server::visible_devices`]
+///
+/// Devices are identified by [`Device::key`], if there are multiple devices with the same
+/// key only the last one is kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceSnapshot {
+    devices: BTreeMap<DeviceKey, Device>,
+}
+
+/// Difference between two [`DeviceSnapshot`]s
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotDiff {
+    /// Devices present only in the newer snapshot
+    pub added: Vec<Device>,
+    /// Devices present only in the older snapshot
+    pub removed: Vec<Device>,
+    /// Devices present in both snapshots but with different information
+    pub changed: Vec<DeviceChange>,
+}
+
+/// Device that changed between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceChange {
+    pub before: Device,
+    pub after: Device,
+    /// Fields that differ between `before` and `after`
+    pub fields: Vec<DeviceField>,
+}
+
+impl DeviceSnapshot {
+    pub fn new(devices: impl IntoIterator<Item = Device>) -> Self {
+        Self {
+            devices: devices.into_iter()
+                .map(|dev| (dev.key(), dev))
+                .collect(),
+        }
+    }
+
+    /// Iterate over devices ordered by their keys
+    pub fn devices(&self) -> impl Iterator<Item = &Device> {
+        self.devices.values()
+    }
+
+    pub fn get(&self, key: &DeviceKey) -> Option<&Device> {
+        self.devices.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Compare with a newer snapshot
+    pub fn diff(&self, newer: &DeviceSnapshot) -> SnapshotDiff {
+        let mut diff = SnapshotDiff::default();
+        for (key, before) in &self.devices {
+            match newer.devices.get(key) {
+                None => diff.removed.push(before.clone()),
+                Some(after) => {
+                    let fields = before.changed_fields(after);
+                    if !fields.is_empty() {
+                        diff.changed.push(DeviceChange {
+                            before: before.clone(),
+                            after: after.clone(),
+                            fields,
+                        });
+                    }
+                },
+            }
+        }
+        diff.added = newer.devices.iter()
+            .filter(|(key, _)| !self.devices.contains_key(key))
+            .map(|(_, dev)| dev.clone())
+            .collect();
+        diff
+    }
+}
+
+impl FromIterator<Device> for DeviceSnapshot {
+    fn from_iter<T: IntoIterator<Item = Device>>(iter: T) -> Self {
+        Self::new(iter)
+    }
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Driver types that can be installed by libwdi
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DriverType {
+    WinUsb,
+    LibUsb0,
+    LibUsbK,
+    Cdc,
+    User,
+}
+
+/// Third-party driver package present in the system
+///
+/// On Windows these are the `oem*.inf` files from the driver store, on Linux the udev rules
+/// files generated by this crate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DriverPackage {
+    /// Published name of the package, e.g. `oem12.inf`
+    pub name: String,
+    /// Provider of the driver package
+    pub provider: Option<String>,
+    /// Device setup class of the driver, e.g. `USBDevice`
+    pub class: Option<String>,
+    /// Driver date and version as specified in the package, e.g. `04/06/2023,6.1.7600.16385`
+    pub version: Option<String>,
+}
+
+impl DriverType {
+    pub const ALL: [DriverType; 5] = [Self::WinUsb, Self::LibUsb0, Self::LibUsbK, Self::Cdc, Self::User];
+}
+
+/// Support of a driver type by the embedded libwdi
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DriverSupport {
+    pub driver: DriverType,
+    pub supported: bool,
+    /// Version of the embedded driver files, `None` if not supported
+    pub version: Option<DriverVersion>,
+    /// Date of the embedded driver files as a Unix timestamp in seconds, often not set
+    pub date: Option<u64>,
+}
+
+/// Driver version in the Windows `major.minor.build.revision` format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct DriverVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub build: u16,
+    pub revision: u16,
+}
+
+impl DriverVersion {
+    /// Create from the most and least significant parts, as stored in `VS_FIXEDFILEINFO`
+    pub fn from_ms_ls(ms: u32, ls: u32) -> Self {
+        Self {
+            major: (ms >> 16) as u16,
+            minor: ms as u16,
+            build: (ls >> 16) as u16,
+            revision: ls as u16,
+        }
+    }
+}
+
+impl From<u64> for DriverVersion {
+    /// Unpack version stored as a single number, e.g. [`Device::driver_version`]
+    fn from(version: u64) -> Self {
+        Self::from_ms_ls((version >> 32) as u32, version as u32)
+    }
+}
+
+impl fmt::Display for DriverVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.major, self.minor, self.build, self.revision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(vendor: &str, inf_name: &str) -> InstallConfig {
+        InstallConfig {
+            vendor: vendor.to_string(),
+            driver_path: "/var/lib/winusb-installer/test".to_string(),
+            inf_name: inf_name.to_string(),
+        }
+    }
+
+    fn device(pid: u16, device_id: &str) -> Device {
+        Device {
+            vid: 0x1209,
+            pid,
+            is_composite: false,
+            mi: None,
+            driver_version: None,
+            desc: "Device".to_string(),
+            driver: None,
+            device_id: Some(device_id.to_string()),
+            hardware_id: None,
+            compatible_id: None,
+            upper_filter: None,
+        }
+    }
+
+    fn with_driver(dev: &Device, driver: &str, version: u64) -> Device {
+        Device {
+            driver: Some(driver.to_string()),
+            driver_version: NonZeroU64::new(version),
+            ..dev.clone()
+        }
+    }
+
+    fn check(path: &str) -> Result<(), ConfigError> {
+        check_windows_driver_path(path, &[r"C:\Program Files".to_string()])
+    }
+
+    #[test]
+    fn valid_config() {
+        assert_eq!(config("Vendor", "MyWinUSB.inf").validate(), Ok(()));
+        assert_eq!(config("Vendor", "my.driver.INF").validate(), Ok(()));
+        assert_eq!(InstallConfig::new("  Vendor ", " a.inf ").canonical().validate(), Ok(()));
+    }
+
+    #[test]
+    fn vendor() {
+        assert_eq!(config("", "a.inf").validate(), Err(ConfigError::EmptyVendor));
+        assert_eq!(config(" \t", "a.inf").validate(), Err(ConfigError::EmptyVendor));
+        for vendor in ["Ven\0dor", "Ven\ndor", "Vendor\r", "\u{1b}[31mVendor", "Ven\u{85}dor"] {
+            assert_eq!(config(vendor, "a.inf").validate(), Err(ConfigError::InvalidVendor(vendor.to_string())));
+        }
+        // Non-ASCII names are fine for the device property, but not used in the default path
+        assert_eq!(config("Müller & Söhne", "a.inf").validate(), Ok(()));
+        assert_eq!(InstallConfig::default_driver_path("Müller & Söhne"), "/var/lib/winusb-installer/M_ller___S_hne");
+        assert_eq!(InstallConfig::default_driver_path("../.."), "/var/lib/winusb-installer/_____");
+    }
+
+    #[test]
+    fn inf_name() {
+        for name in ["", "dir/a.inf", r"dir\a.inf", r"..\a.inf", "../a.inf", "C:a.inf", "a?.inf", "a\0.inf", ".", "..", "..."] {
+            assert_eq!(config("Vendor", name).validate(), Err(ConfigError::InfNameNotFileName(name.to_string())), "{:?}", name);
+        }
+        for name in ["a", ".inf", "a.inf.", "a.txt", "a.inf.txt", "ainf"] {
+            assert_eq!(config("Vendor", name).validate(), Err(ConfigError::InfNameExtension(name.to_string())), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_windows_path(r"C:\Program Files\x"), r"C:\Program Files\x");
+        assert_eq!(normalize_windows_path(r"C:/Program Files//.\x\"), r"C:\Program Files\x");
+        assert_eq!(normalize_windows_path(r"C:\Program Files\..\x"), r"C:\Program Files\..\x");
+        assert_eq!(normalize_windows_path(r"\\server\share\x"), r"\\server\share\x");
+        assert_eq!(normalize_windows_path("//server/share/x"), r"\\server\share\x");
+        assert_eq!(normalize_windows_path(r"\\?\C:\x"), r"\\?\C:\x");
+        assert_eq!(normalize_windows_path(r"/\server\x"), r"\\server\x");
+        assert_eq!(normalize_windows_path("C:"), r"C:\");
+        assert_eq!(normalize_windows_path("C:/"), r"C:\");
+        assert_eq!(normalize_windows_path(r"x\y"), r"x\y");
+    }
+
+    #[test]
+    fn protected_path() {
+        assert_eq!(check(r"C:\Program Files\Vendor"), Ok(()));
+        assert_eq!(check(r"C:\Program Files\Vendor\driver\"), Ok(()));
+        assert_eq!(check("c:/program files/VENDOR"), Ok(()));
+        assert_eq!(check(r"C:/PROGRAM FILES\\Vendor/./driver"), Ok(()));
+        assert_eq!(check_windows_driver_path(r"C:\Program Files\Vendor", &[r"c:/program files/".to_string()]), Ok(()));
+    }
+
+    #[test]
+    fn network_path() {
+        for path in [r"\\server\share\x", "//server/share/x", r"\\?\C:\Program Files\x", r"\\.\C:\Program Files\x", r"\/server\x"] {
+            assert_eq!(check(path), Err(ConfigError::NetworkDriverPath(path.to_string())), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn relative_path() {
+        for path in ["", "x", r"Program Files\x", r"\Program Files\x", "C:", r"C:Program Files\x", r".\x", r"..\x", r"1:\x"] {
+            assert_eq!(check(path), Err(ConfigError::RelativeDriverPath(path.to_string())), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn path_traversal() {
+        for path in [r"C:\Program Files\..\Users\x", "C:/Program Files/x/../../Users", r"C:\Program Files\x\.."] {
+            assert_eq!(check(path), Err(ConfigError::DriverPathTraversal(path.to_string())), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn unprotected_path() {
+        for path in [r"C:\", "C:/", r"C:\Program Files", r"C:\Program Files\", r"C:\Program Files Evil\x",
+            r"C:\Program Files (x86)\x", r"C:\Program FilesEvil", r"D:\Program Files\x", r"C:\Users\x\Program Files\x"]
+        {
+            assert_eq!(check(path), Err(ConfigError::UnprotectedDriverPath(path.to_string())), "{:?}", path);
+        }
+        // Nothing is protected if the directories could not be determined
+        assert!(check_windows_driver_path(r"C:\Program Files\x", &[]).is_err());
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        let a = device(0x0001, "1-1");
+        let b = device(0x0002, "1-2");
+        let c = device(0x0003, "1-3");
+        let winusb = with_driver(&a, "WinUSB", 1 << 48);
+        let updated = with_driver(&a, "WinUSB", 2 << 48);
+        let change = |before: &Device, after: &Device, fields: Vec<DeviceField>| DeviceChange {
+            before: before.clone(),
+            after: after.clone(),
+            fields,
+        };
+
+        let cases = [
+            (vec![], vec![], SnapshotDiff::default()),
+            (vec![a.clone(), b.clone()], vec![b.clone(), a.clone()], SnapshotDiff::default()),
+            (vec![a.clone()], vec![a.clone(), b.clone(), c.clone()], SnapshotDiff {
+                added: vec![b.clone(), c.clone()],
+                ..SnapshotDiff::default()
+            }),
+            (vec![a.clone(), b.clone()], vec![b.clone()], SnapshotDiff {
+                removed: vec![a.clone()],
+                ..SnapshotDiff::default()
+            }),
+            (vec![a.clone(), b.clone()], vec![winusb.clone(), b.clone()], SnapshotDiff {
+                changed: vec![change(&a, &winusb, vec![DeviceField::DriverVersion, DeviceField::Driver])],
+                ..SnapshotDiff::default()
+            }),
+            (vec![winusb.clone()], vec![updated.clone()], SnapshotDiff {
+                changed: vec![change(&winusb, &updated, vec![DeviceField::DriverVersion])],
+                ..SnapshotDiff::default()
+            }),
+            (vec![a.clone(), b.clone()], vec![updated.clone(), c.clone()], SnapshotDiff {
+                added: vec![c.clone()],
+                removed: vec![b.clone()],
+                changed: vec![change(&a, &updated, vec![DeviceField::DriverVersion, DeviceField::Driver])],
+            }),
+            // Same device on another port is a different device
+            (vec![a.clone()], vec![device(0x0001, "1-4")], SnapshotDiff {
+                added: vec![device(0x0001, "1-4")],
+                removed: vec![a.clone()],
+                ..SnapshotDiff::default()
+            }),
+        ];
+        for (i, (older, newer, expected)) in cases.into_iter().enumerate() {
+            let diff = DeviceSnapshot::new(older).diff(&DeviceSnapshot::new(newer));
+            assert_eq!(diff.is_empty(), expected == SnapshotDiff::default(), "case {}", i);
+            assert_eq!(diff, expected, "case {}", i);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Strategies for launching the client process
+//!
+//! [`crate::Server`] uses an [`Elevator`] to start the client. By default the client is
+//! spawned with admin privileges ([`Runas`] on Windows, [`Pkexec`] on Linux) or runs in
+//! the current process ([`InProcess`]) if it already has admin privileges.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+
+/// Description of the client process to be launched
+#[derive(Debug, Clone)]
+pub struct ClientCommand {
+    /// Path to the client executable
+    pub executable: PathBuf,
+    /// Arguments to pass to the client (without the program name)
+    pub args: Vec<OsString>,
+    /// Should the client window be visible (if applicable)
+    pub show_window: bool,
+    /// Working directory of the client, if not set it depends on the elevator
+    pub current_dir: Option<PathBuf>,
+    /// Configuration of clients that run in the current process
+    pub in_process: InProcessConfig,
+}
+
+/// Configuration of the client that a spawned client executable would set itself, but that
+/// has to be passed to clients running in the current process, see [`InProcess`]
+#[derive(Debug, Clone, Default)]
+pub struct InProcessConfig {
+    /// See [`crate::Client::operations`]
+    pub operations: crate::Operations,
+    /// See [`crate::Client::security_policy`]
+    pub policy: crate::SecurityPolicy,
+    /// See [`crate::Client::root`]
+    #[cfg(target_os = "linux")]
+    pub root: Option<PathBuf>,
+}
+
+/// Handle to a running client
+pub trait ClientProcess: Send {
+    /// Check if the client exited, without blocking
+    fn try_wait(&mut self) -> io::Result<bool>;
+
+    /// Terminate the client, succeeds if the client already exited
+    fn kill(&mut self) -> io::Result<()>;
+
+    /// Asynchronously wait until the client exits
+    fn wait_async(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let poll_period = Duration::from_millis(50);
+            while !self.try_wait()? {
+                tokio::time::sleep(poll_period).await;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Strategy of launching the client process
+pub trait Elevator: Send + Sync {
+    /// Launch the client described by `command`
+    fn spawn(&self, command: &ClientCommand) -> io::Result<Box<dyn ClientProcess>>;
+}
+
+/// Elevator used by default on current platform when the process is not elevated
+#[cfg(windows)]
+pub fn platform_default() -> Box<dyn Elevator> {
+    Box::new(Runas)
+}
+
+/// Elevator used by default on current platform when the process is not elevated
+#[cfg(unix)]
+pub fn platform_default() -> Box<dyn Elevator> {
+    Box::new(Pkexec)
+}
+
+/// Check if the current process already has admin privileges
+#[cfg(windows)]
+pub fn is_elevated() -> io::Result<bool> {
+    crate::runas::is_elevated()
+}
+
+/// Check if the current process already has admin privileges
+#[cfg(unix)]
+pub fn is_elevated() -> io::Result<bool> {
+    Ok(unsafe { libc::geteuid() } == 0)
+}
+
+/// Check if the process with given PID is still running
+#[cfg(windows)]
+pub fn is_process_alive(pid: u32) -> bool {
+    crate::runas::is_process_alive(pid)
+}
+
+/// Check if the process with given PID is still running
+#[cfg(unix)]
+pub fn is_process_alive(pid: u32) -> bool {
+    // Signal 0 only performs the existence and permission checks
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Spawn the client with Windows "runas" verb, user will be asked for consent in UAC prompt
+#[cfg(windows)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Runas;
+
+#[cfg(windows)]
+impl Elevator for Runas {
+    fn spawn(&self, command: &ClientCommand) -> io::Result<Box<dyn ClientProcess>> {
+        let mut cmd = crate::runas::Command::new(&command.executable);
+        cmd.args(&command.args).hide(!command.show_window);
+        if let Some(dir) = &command.current_dir {
+            cmd.current_dir(dir);
+        }
+        Ok(Box::new(cmd.spawn()?))
+    }
+}
+
+#[cfg(windows)]
+impl ClientProcess for crate::runas::Child {
+    fn try_wait(&mut self) -> io::Result<bool> {
+        crate::runas::Child::try_wait(self, Duration::ZERO).map(|exited| exited.is_some())
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        crate::runas::Child::kill(self)
+    }
+
+    fn wait_async(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(crate::runas::Child::wait_async(self))
+    }
+}
+
+/// Run the client on a separate thread of the current process
+///
+/// Can be used when the current process already has admin privileges. The client still
+/// communicates with the server over IPC, so the whole installation process stays the same,
+/// except that the environment variables of the server are not applied. The client is
+/// configured using [`ClientCommand::in_process`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InProcess;
+
+impl Elevator for InProcess {
+    fn spawn(&self, command: &ClientCommand) -> io::Result<Box<dyn ClientProcess>> {
+        let mut client = crate::Client::from_args(&command.args)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid client arguments"))?;
+        let config = &command.in_process;
+        client.operations(config.operations.clone())
+            .security_policy(config.policy.clone())
+            .in_process();
+        #[cfg(target_os = "linux")]
+        if let Some(root) = &config.root {
+            client.root(root);
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let thread = thread::Builder::new()
+            .name("winusb-installer-client".into())
+            .spawn(move || {
+                if let Err(err) = runtime.block_on(client.serve()) {
+                    log::error!("In-process client failed: {}", err);
+                }
+            })?;
+        Ok(Box::new(ClientThread(thread)))
+    }
+}
+
+struct ClientThread(thread::JoinHandle<()>);
+
+impl ClientProcess for ClientThread {
+    fn try_wait(&mut self) -> io::Result<bool> {
+        Ok(self.0.is_finished())
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        // Threads cannot be terminated, the client finishes when the connection gets closed
+        if !self.0.is_finished() {
+            log::debug!("In-process client still running, waiting for it to disconnect");
+        }
+        Ok(())
+    }
+}
+
+/// Spawn the client as a regular process without elevating privileges
+///
+/// Mostly useful for testing the whole installation flow without admin privileges.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unelevated;
+
+impl Elevator for Unelevated {
+    fn spawn(&self, command: &ClientCommand) -> io::Result<Box<dyn ClientProcess>> {
+        let mut cmd = process::Command::new(&command.executable);
+        cmd.args(&command.args);
+        if let Some(dir) = &command.current_dir {
+            cmd.current_dir(dir);
+        }
+        #[cfg(windows)]
+        if !command.show_window {
+            use std::os::windows::process::CommandExt;
+            const CREATE_NO_WINDOW: u32 = 0x08000000;
+            cmd.creation_flags(CREATE_NO_WINDOW);
+        }
+        Ok(Box::new(cmd.spawn()?))
+    }
+}
+
+/// Spawn the client through `pkexec`, user will be asked for authentication by polkit agent
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pkexec;
+
+#[cfg(unix)]
+impl Elevator for Pkexec {
+    fn spawn(&self, command: &ClientCommand) -> io::Result<Box<dyn ClientProcess>> {
+        // pkexec always starts the program in the home directory of the target user
+        let child = process::Command::new("pkexec")
+            .arg(&command.executable)
+            .args(&command.args)
+            .spawn()?;
+        Ok(Box::new(child))
+    }
+}
+
+/// Spawn the client through non-interactive `sudo -n`
+///
+/// Fails if sudo would need to ask for a password, so it is only useful when passwordless
+/// sudo has been configured (e.g. on CI machines).
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sudo;
+
+#[cfg(unix)]
+impl Elevator for Sudo {
+    fn spawn(&self, command: &ClientCommand) -> io::Result<Box<dyn ClientProcess>> {
+        let mut cmd = process::Command::new("sudo");
+        cmd.arg("-n")
+            .arg("--")
+            .arg(&command.executable)
+            .args(&command.args);
+        if let Some(dir) = &command.current_dir {
+            cmd.current_dir(dir);
+        }
+        Ok(Box::new(cmd.spawn()?))
+    }
+}
+
+impl ClientProcess for process::Child {
+    fn try_wait(&mut self) -> io::Result<bool> {
+        process::Child::try_wait(self).map(|status| status.is_some())
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        // Don't kill if it already exited
+        if process::Child::try_wait(self)?.is_some() {
+            return Ok(());
+        }
+        process::Child::kill(self)
+    }
+}
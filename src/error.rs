@@ -0,0 +1,102 @@
+//! Errors reported by the installation process
+
+use std::fmt;
+use std::io;
+
+use serde::{Serialize, Deserialize};
+
+use crate::{ConfigError, PolicyViolation};
+
+/// Error of the installation process
+#[derive(Debug)]
+pub enum Error {
+    /// User declined the elevation request (clicked "No" in the UAC prompt)
+    ///
+    /// Nothing has been installed, the installation can be retried.
+    ElevationDeclined,
+    /// Current process does not have admin privileges required by
+    /// [`crate::ExecutionMode::InProcess`]
+    NotElevated,
+    /// Client process could not be spawned
+    Spawn(io::Error),
+    /// Client process exited before the installation has been finished
+    ClientExited,
+    /// Client reported an error while handling the request
+    Client(String),
+    /// Client panicked or failed with an unrecoverable error
+    ClientFatal(ClientFatal),
+    /// Client rejected the request because of its [`crate::SecurityPolicy`]
+    PolicyViolation(PolicyViolation),
+    /// Installation configuration is invalid, see [`crate::InstallConfig::validate`]
+    InvalidConfig(ConfigError),
+    /// Communication with the client failed
+    Io(io::Error),
+}
+
+impl Error {
+    /// Classify an error returned when spawning the client process
+    #[cfg(windows)]
+    pub(crate) fn from_spawn(err: io::Error) -> Self {
+        match crate::runas::SpawnError::from_io(&err) {
+            Some(crate::runas::SpawnError::Cancelled) => Self::ElevationDeclined,
+            _ => Self::Spawn(err),
+        }
+    }
+
+    /// Classify an error returned when spawning the client process
+    #[cfg(unix)]
+    pub(crate) fn from_spawn(err: io::Error) -> Self {
+        Self::Spawn(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ElevationDeclined => write!(f, "User declined the elevation request"),
+            Self::NotElevated => write!(f, "Current process is not elevated, cannot install in process"),
+            Self::Spawn(err) => write!(f, "Could not spawn client process: {}", err),
+            Self::ClientExited => write!(f, "Client process exited unexpectedly"),
+            Self::Client(err) => write!(f, "Client error: {}", err),
+            Self::ClientFatal(fatal) => write!(f, "Client failed: {}", fatal),
+            Self::PolicyViolation(violation) => write!(f, "Rejected by client policy: {}", violation),
+            Self::InvalidConfig(err) => write!(f, "Invalid installation configuration: {}", err),
+            Self::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Spawn(err) | Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Details of the failure reported by the client before exiting, see [`Error::ClientFatal`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientFatal {
+    /// Panic message or error description
+    pub message: String,
+    /// Source location of the panic
+    pub location: Option<String>,
+    /// Backtrace captured when the client panicked
+    pub backtrace: Option<String>,
+}
+
+impl fmt::Display for ClientFatal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{} at {}", self.message, location),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! Options and results of the installation process shared by server and client
+
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+use crate::Device;
+
+/// Options sent to the client together with the installation request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallOptions {
+    /// Verify that devices use the new driver after installation, disabled if `None`
+    pub verify: Option<VerifyOptions>,
+    /// Retrying installation after transient failures
+    pub retry: RetryPolicy,
+    /// Handling of devices that already use the driver
+    pub policy: InstallPolicy,
+    /// Number of hardware IDs installed at the same time, 0 and 1 mean sequential installation
+    pub concurrency: usize,
+    /// Time without server heartbeats after which the client cancels the installation
+    pub heartbeat_tolerance: Duration,
+}
+
+impl Default for InstallOptions {
+    fn default() -> Self {
+        Self {
+            verify: None,
+            retry: RetryPolicy::default(),
+            policy: InstallPolicy::default(),
+            concurrency: 1,
+            heartbeat_tolerance: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Determines if the driver is installed for devices that already use it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum InstallPolicy {
+    /// Skip devices that already use the driver
+    #[default]
+    SkipIfPresent,
+    /// Skip devices that already use the driver, unless its version is older than the one
+    /// that would be installed
+    ///
+    /// On Linux there are no driver versions, so this is the same as [`Self::SkipIfPresent`].
+    ReinstallIfOlder,
+    /// Always install the driver
+    Always,
+}
+
+/// Post-installation verification performed by the client
+///
+/// Windows sometimes keeps the old driver until the device is replugged even though the
+/// installation succeeded. The client re-enumerates devices until the device uses the
+/// installed driver or the timeout passes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyOptions {
+    /// How long to wait for the device to bind to the new driver
+    pub timeout: Duration,
+    /// Time between device enumerations
+    pub interval: Duration,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            interval: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Retry policy for per-device installation failures
+///
+/// A device is installed at most `max_attempts` times. Installation is retried only if it
+/// failed with one of the `retryable` error codes, the delay before `n`-th retry is
+/// `backoff * backoff_factor^(n-1)`, at most `max_backoff`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Maximum number of installation attempts per device, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub backoff: Duration,
+    /// Multiplier of the delay applied after each retry
+    pub backoff_factor: u32,
+    /// Upper limit of the delay between retries
+    pub max_backoff: Duration,
+    /// Error codes that are considered transient
+    pub retryable: Vec<ErrorCode>,
+}
+
+impl Default for RetryPolicy {
+    /// No retries, but with backoff and error codes set to values sensible when only
+    /// `max_attempts` gets changed
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Duration::from_secs(2),
+            backoff_factor: 2,
+            max_backoff: Duration::from_secs(60),
+            retryable: vec![
+                ErrorCode::Busy,
+                ErrorCode::Timeout,
+                ErrorCode::PendingInstallation,
+                ErrorCode::Interrupted,
+                ErrorCode::Resource,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Check if installation should be retried after given attempt (counted from 1) failed
+    pub fn should_retry(&self, attempt: u32, code: ErrorCode) -> bool {
+        attempt < self.max_attempts && self.retryable.contains(&code)
+    }
+
+    /// Delay before retrying after given attempt (counted from 1) failed
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.backoff_factor.saturating_pow(attempt.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Installation error codes, corresponding to libwdi error codes
+///
+/// On Linux these are derived from [`std::io::ErrorKind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ErrorCode {
+    Io,
+    Access,
+    NoDevice,
+    NotFound,
+    Busy,
+    Timeout,
+    Overflow,
+    PendingInstallation,
+    Interrupted,
+    Resource,
+    NotSupported,
+    Exists,
+    UserCancel,
+    NeedsAdmin,
+    Wow64,
+    InfSyntax,
+    CatMissing,
+    Unsigned,
+    Other,
+}
+
+/// Error of a single installation attempt
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstallError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl std::fmt::Display for InstallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:?})", self.message, self.code)
+    }
+}
+
+/// Result of the installation for a single device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InstallOutcome {
+    /// Driver has been installed (and verified, if enabled)
+    Installed,
+    /// Driver has been installed but the device did not bind to it during verification,
+    /// replugging the device may be needed
+    InstalledButNotBound {
+        /// Driver used by the device at the end of verification, `None` if the device
+        /// could not be found
+        driver: Option<String>,
+    },
+    /// Installation failed, with the error of the last attempt
+    Failed(InstallError),
+    /// Device already used the driver so it has been skipped according to [`InstallPolicy`]
+    Skipped,
+}
+
+impl InstallOutcome {
+    /// Check if the driver has been installed, even if the device does not use it yet
+    pub fn is_installed(&self) -> bool {
+        matches!(self, Self::Installed | Self::InstalledButNotBound { .. })
+    }
+
+    /// Check if the driver is present for the device, either installed or skipped
+    pub fn has_driver(&self) -> bool {
+        self.is_installed() || matches!(self, Self::Skipped)
+    }
+}
+
+/// Final result of the installation for a single device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceReport {
+    pub device: Device,
+    pub outcome: InstallOutcome,
+    /// Number of installation attempts made, 0 if skipped
+    pub attempts: u32,
+    /// Errors of the attempts that have been retried
+    pub retried_errors: Vec<InstallError>,
+    /// Driver changes for the device only take effect after reboot
+    pub reboot_required: bool,
+}
+
+/// Summary of the installation, see [`crate::Server::install`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstallReport {
+    /// Results for devices in the order in which they have been installed, followed by the
+    /// requested devices that have not been installed
+    pub devices: Vec<DeviceReport>,
+    /// System had operations pending until reboot after the installation, see
+    /// [`crate::Server::reboot_pending`]
+    pub reboot_pending: bool,
+}
+
+impl InstallReport {
+    /// Number of devices for which the driver has been installed
+    pub fn installed(&self) -> usize {
+        self.devices.iter()
+            .filter(|report| report.outcome.is_installed())
+            .count()
+    }
+
+    /// Number of devices skipped because they already used the driver
+    pub fn skipped(&self) -> usize {
+        self.devices.iter()
+            .filter(|report| report.outcome == InstallOutcome::Skipped)
+            .count()
+    }
+
+    /// Check if all devices have the driver, either installed or skipped
+    pub fn all_installed(&self) -> bool {
+        self.devices.iter().all(|report| report.outcome.has_driver())
+    }
+
+    /// Check if the user should reboot for the installation to take effect
+    pub fn reboot_required(&self) -> bool {
+        self.reboot_pending || self.devices.iter().any(|report| report.reboot_required)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy { max_attempts, ..RetryPolicy::default() }
+    }
+
+    #[test]
+    fn retry_until_max_attempts() {
+        let policy = policy(3);
+        assert!(policy.should_retry(1, ErrorCode::Busy));
+        assert!(policy.should_retry(2, ErrorCode::Busy));
+        assert!(!policy.should_retry(3, ErrorCode::Busy));
+        assert!(!RetryPolicy::default().should_retry(1, ErrorCode::Busy));
+    }
+
+    #[test]
+    fn retry_only_retryable_codes() {
+        let policy = policy(3);
+        for code in [ErrorCode::Busy, ErrorCode::Timeout, ErrorCode::PendingInstallation, ErrorCode::Interrupted, ErrorCode::Resource] {
+            assert!(policy.should_retry(1, code), "{:?}", code);
+        }
+        for code in [ErrorCode::Access, ErrorCode::NotFound, ErrorCode::InfSyntax, ErrorCode::Unsigned, ErrorCode::Other] {
+            assert!(!policy.should_retry(1, code), "{:?}", code);
+        }
+        let policy = RetryPolicy { retryable: Vec::new(), ..policy };
+        assert!(!policy.should_retry(1, ErrorCode::Busy));
+    }
+
+    #[test]
+    fn exponential_delay() {
+        let policy = policy(5);
+        assert_eq!(policy.delay(1), Duration::from_secs(2));
+        assert_eq!(policy.delay(2), Duration::from_secs(4));
+        assert_eq!(policy.delay(3), Duration::from_secs(8));
+        // Attempts are counted from 1, 0 is treated as the first one
+        assert_eq!(policy.delay(0), Duration::from_secs(2));
+
+        let constant = RetryPolicy { backoff_factor: 1, ..policy };
+        assert_eq!(constant.delay(10), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn delay_clamped() {
+        let policy = RetryPolicy { max_backoff: Duration::from_secs(10), ..policy(100) };
+        assert_eq!(policy.delay(3), Duration::from_secs(8));
+        assert_eq!(policy.delay(4), Duration::from_secs(10));
+        // The factor saturates instead of overflowing
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(10));
+
+        let unlimited = RetryPolicy {
+            backoff: Duration::MAX,
+            backoff_factor: u32::MAX,
+            max_backoff: Duration::MAX,
+            ..policy
+        };
+        assert_eq!(unlimited.delay(u32::MAX), Duration::MAX);
+    }
+}
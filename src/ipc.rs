@@ -1,32 +1,209 @@
-//! Client/server interprocess communication using Windows named pipes
+//! Client/server interprocess communication using Windows named pipes (Unix domain sockets
+//! on Linux)
+//!
+//! Messages are length delimited and encoded using a [`Format`] selected by the server. The
+//! server announces the format in the first frame after the client connects. Requests and
+//! responses are built on top of the message channels in [`rpc`].
 
-use std::io;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
 use std::marker::PhantomData;
-use std::time::Duration;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use bincode::Options;
+use bytes::{Bytes, BytesMut};
 use futures::future::BoxFuture;
-use windows::Win32::Foundation::ERROR_PIPE_BUSY;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::windows::named_pipe::{NamedPipeServer, NamedPipeClient, ServerOptions, ClientOptions};
+use futures::{SinkExt, StreamExt};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
 use tokio_util::codec;
-use tokio_serde::formats::Bincode;
+
+pub use transport::{Listener, ServerIo, ClientIo};
+
+pub mod rpc;
+
+/// Limits applied when receiving messages
+///
+/// Frames longer than `max_frame_length` are rejected before being read into memory. Decoding
+/// fails if a message would need more than `max_message_size` bytes, this also bounds the
+/// lengths of sequences and strings, so a small frame cannot cause a huge allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum length of a single frame in bytes
+    pub max_frame_length: usize,
+    /// Maximum number of bytes decoded for a single message
+    pub max_message_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame_length: 8 * 1024 * 1024,
+            max_message_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Encoding of the messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Compact binary encoding
+    #[default]
+    Bincode,
+    /// Human-readable encoding, useful when inspecting the traffic
+    Json,
+}
+
+impl Format {
+    /// Environment variable read by [`Self::from_env`]
+    pub const ENV_VAR: &str = "WINUSB_INSTALLER_IPC_FORMAT";
+
+    /// Format named by [`Self::ENV_VAR`] (`bincode` or `json`), [`Format::Bincode`] if not set
+    pub fn from_env() -> Self {
+        match env::var(Self::ENV_VAR) {
+            Ok(name) => Self::from_name(&name).unwrap_or_else(|| {
+                log::warn!("Unknown {}={:?}, using bincode", Self::ENV_VAR, name);
+                Self::Bincode
+            }),
+            Err(_) => Self::Bincode,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bincode => "bincode",
+            Self::Json => "json",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "bincode" => Some(Self::Bincode),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Direction in which a message has been sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    ServerToClient,
+    ClientToServer,
+}
+
+impl Direction {
+    fn reverse(self) -> Self {
+        match self {
+            Self::ServerToClient => Self::ClientToServer,
+            Self::ClientToServer => Self::ServerToClient,
+        }
+    }
+}
+
+/// Single message of a recording, stored as a line of JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    /// Time since the recording started
+    pub elapsed: Duration,
+    pub direction: Direction,
+    /// Message in its JSON representation, independent of the negotiated [`Format`]
+    pub message: serde_json::Value,
+}
+
+impl Record {
+    /// Decode the recorded message
+    pub fn decode<T: DeserializeOwned>(&self) -> io::Result<T> {
+        Ok(T::deserialize(&self.message)?)
+    }
+}
+
+/// Writes every message sent or received through a channel to a file, see [`Server::record`]
+pub struct Recorder {
+    start: Instant,
+    file: Mutex<io::BufWriter<fs::File>>,
+}
+
+impl Recorder {
+    /// Create the recording file, truncating it if it exists
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = fs::File::create(path)?;
+        Ok(Self {
+            start: Instant::now(),
+            file: Mutex::new(io::BufWriter::new(file)),
+        })
+    }
+
+    fn record<T: Serialize>(&self, direction: Direction, msg: &T) {
+        let result = serde_json::to_value(msg)
+            .map(|message| Record { elapsed: self.start.elapsed(), direction, message })
+            .map_err(io::Error::from)
+            .and_then(|record| {
+                let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+                serde_json::to_writer(&mut *file, &record)?;
+                // Flush each message so that the recording survives a crash
+                file.write_all(b"\n")?;
+                file.flush()
+            });
+        if let Err(err) = result {
+            log::warn!("Could not record message: {}", err);
+        }
+    }
+}
+
+/// Read a recording created by [`Recorder`]
+pub fn read_recording(path: impl AsRef<Path>) -> io::Result<Vec<Record>> {
+    let file = io::BufReader::new(fs::File::open(path)?);
+    let mut records = Vec::new();
+    for line in file.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            records.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(records)
+}
 
 /// Server that must wait for client connection to be used
 pub struct Server<Source, Sink> {
-    inner: NamedPipeServer,
+    inner: Listener,
+    limits: Limits,
+    format: Format,
+    recorder: Option<Arc<Recorder>>,
     _source: PhantomData<Source>,
     _sink: PhantomData<Sink>,
 }
 
 impl<Source, Sink> Server<Source, Sink> {
-    pub async fn connect(self) -> io::Result<Channel<NamedPipeServer, Source, Sink>> {
-        self.inner.connect().await?;
-        Ok(channel(self.inner))
+    /// Use given message format instead of [`Protocol::format`]
+    pub fn format(&mut self, format: Format) -> &mut Self {
+        self.format = format;
+        self
+    }
+
+    /// Record all messages of the connection
+    pub fn record(&mut self, recorder: Recorder) -> &mut Self {
+        self.recorder = Some(Arc::new(recorder));
+        self
+    }
+
+    pub async fn connect(self) -> io::Result<Channel<ServerIo, Source, Sink>> {
+        let io = self.inner.accept().await?;
+        let mut framed = length_delimited(io, self.limits.max_frame_length);
+        framed.send(Bytes::from_static(self.format.name().as_bytes())).await?;
+        log::debug!("Using {} message format", self.format.name());
+        let mut codec = MsgCodec::new(self.format, self.limits.max_message_size);
+        codec.recorder = self.recorder.map(|recorder| (recorder, Direction::ServerToClient));
+        Ok(tokio_serde::Framed::new(framed, codec))
     }
 }
 
 /// Result of an attempt to connect client to a server
-pub type ClientConnectFuture<'a, ServerMsg, ClientMsg> = BoxFuture<'a, io::Result<Channel<NamedPipeClient, ServerMsg, ClientMsg>>>;
+pub type ClientConnectFuture<'a, ServerMsg, ClientMsg> = BoxFuture<'a, io::Result<Channel<ClientIo, ServerMsg, ClientMsg>>>;
 
 /// Protocol between server and client
 pub trait Protocol {
@@ -36,16 +213,52 @@ pub trait Protocol {
     /// Messages sent by the client
     type ClientMsg;
 
+    /// Message format used by servers, clients use the format announced by the server
+    fn format() -> Format {
+        Format::from_env()
+    }
+
+    /// Limits applied by the server when receiving client messages
+    fn client_msg_limits() -> Limits {
+        Limits::default()
+    }
+
+    /// Limits applied by the client when receiving server messages
+    fn server_msg_limits() -> Limits {
+        Limits::default()
+    }
+
     /// Create a server on given Windows pipe
     fn server(pipe_name: &str) -> io::Result<Server<Self::ClientMsg, Self::ServerMsg>> {
+        Self::server_with_limits(pipe_name, Self::client_msg_limits())
+    }
+
+    /// Same as [`Self::server`] but with custom limits for received messages
+    fn server_with_limits(pipe_name: &str, limits: Limits) -> io::Result<Server<Self::ClientMsg, Self::ServerMsg>> {
         server_create(pipe_name)
-            .map(|s| Server { inner: s, _source: PhantomData, _sink: PhantomData })
+            .map(|s| Server {
+                inner: s,
+                limits,
+                format: Self::format(),
+                recorder: None,
+                _source: PhantomData,
+                _sink: PhantomData,
+            })
     }
 
     /// Try connecting to a server on given Windows pipe with a timeout
     fn client(pipe_name: &str, timeout: Duration) -> ClientConnectFuture<'_, Self::ServerMsg, Self::ClientMsg> {
+        Self::client_with_limits(pipe_name, timeout, Self::server_msg_limits())
+    }
+
+    /// Same as [`Self::client`] but with custom limits for received messages
+    fn client_with_limits(
+        pipe_name: &str,
+        timeout: Duration,
+        limits: Limits,
+    ) -> ClientConnectFuture<'_, Self::ServerMsg, Self::ClientMsg> {
         Box::pin(async move {
-            client_connect::<Self::ServerMsg, Self::ClientMsg>(pipe_name, timeout).await
+            client_connect::<Self::ServerMsg, Self::ClientMsg>(pipe_name, timeout, limits).await
         })
     }
 }
@@ -59,13 +272,13 @@ pub trait ProtocolTypes {
 
 impl<T: Protocol> ProtocolTypes for T {
     type Server = Server<T::ClientMsg, T::ServerMsg>;
-    type ServerChannel = Channel<NamedPipeServer, T::ClientMsg, T::ServerMsg>;
-    type ClientChannel = Channel<NamedPipeClient, T::ServerMsg, T::ClientMsg>;
+    type ServerChannel = Channel<ServerIo, T::ClientMsg, T::ServerMsg>;
+    type ClientChannel = Channel<ClientIo, T::ServerMsg, T::ClientMsg>;
 }
 
 
-// Combines length delimiting and serde
-type Channel<IO, Source, Sink> =
+/// Message channel over `IO`, receiving `Source` and sending `Sink` messages
+pub type Channel<IO, Source, Sink> =
     Serde<LengthDelimited<IO>, Source, Sink>;
 
 // At lowest level framing is done by length delimiting
@@ -75,42 +288,335 @@ type LengthDelimited<IO> = codec::Framed<IO, codec::LengthDelimitedCodec>;
 type Serde<InnerIo, SourceItem, SinkItem> =
     tokio_serde::Framed<InnerIo, SourceItem, SinkItem, MsgCodec<SourceItem, SinkItem>>;
 
-// Messages are encoded using bincode
-type MsgCodec<SourceItem, SinkItem> = Bincode<SourceItem, SinkItem>;
+/// Messages are encoded using the negotiated [`Format`], with a size limit when decoding
+pub struct MsgCodec<SourceItem, SinkItem> {
+    format: Format,
+    max_message_size: u64,
+    /// Recorder and the direction of sent messages
+    recorder: Option<(Arc<Recorder>, Direction)>,
+    _marker: PhantomData<(SourceItem, SinkItem)>,
+}
+
+impl<SourceItem, SinkItem> MsgCodec<SourceItem, SinkItem> {
+    fn new(format: Format, max_message_size: u64) -> Self {
+        Self { format, max_message_size, recorder: None, _marker: PhantomData }
+    }
+}
+
+impl<SourceItem: DeserializeOwned + Serialize, SinkItem> tokio_serde::Deserializer<SourceItem> for MsgCodec<SourceItem, SinkItem> {
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<SourceItem> {
+        let item = decode(src, self.format, self.max_message_size)?;
+        if let Some((recorder, sent)) = &self.recorder {
+            recorder.record(sent.reverse(), &item);
+        }
+        Ok(item)
+    }
+}
+
+impl<SourceItem, SinkItem: Serialize> tokio_serde::Serializer<SinkItem> for MsgCodec<SourceItem, SinkItem> {
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> io::Result<Bytes> {
+        if let Some((recorder, sent)) = &self.recorder {
+            recorder.record(*sent, item);
+        }
+        let data = match self.format {
+            Format::Bincode => bincode_options(u64::MAX).serialize(item)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            Format::Json => serde_json::to_vec(item)?,
+        };
+        Ok(Bytes::from(data))
+    }
+}
+
+fn bincode_options(limit: u64) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(limit)
+        .reject_trailing_bytes()
+}
 
+/// Decode a single message, failing on trailing bytes or when exceeding `max_message_size`
+pub fn decode<T: DeserializeOwned>(data: &[u8], format: Format, max_message_size: u64) -> io::Result<T> {
+    match format {
+        // Decoding from a slice ignores the size limit, so decode from a reader and check
+        // trailing bytes manually
+        Format::Bincode => {
+            let mut reader = data;
+            let item = bincode_options(max_message_size)
+                .deserialize_from(&mut reader)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if !reader.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Trailing bytes after message"));
+            }
+            Ok(item)
+        },
+        // JSON cannot allocate more than its length, serde_json rejects trailing characters
+        Format::Json if data.len() as u64 > max_message_size => {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "Message size limit exceeded"))
+        },
+        Format::Json => Ok(serde_json::from_slice(data)?),
+    }
+}
+
+fn length_delimited<T: AsyncRead + AsyncWrite>(io: T, max_frame_length: usize) -> LengthDelimited<T> {
+    let codec = codec::LengthDelimitedCodec::builder()
+        .max_frame_length(max_frame_length)
+        .new_codec();
+    codec::Framed::new(io, codec)
+}
+
+fn channel<IO: AsyncWrite + AsyncRead, Source, Sink>(framed: LengthDelimited<IO>, format: Format, limits: Limits) -> Channel<IO, Source, Sink> {
+    tokio_serde::Framed::new(framed, MsgCodec::new(format, limits.max_message_size))
+}
 
-fn length_delimited<T: AsyncRead + AsyncWrite>(io: T) -> LengthDelimited<T> {
-    codec::Framed::new(io, codec::LengthDelimitedCodec::new())
+/// Pair of channels connected through an in-memory stream, used for replaying recordings
+pub fn duplex<A, B>(format: Format, limits: Limits) -> (Channel<DuplexStream, A, B>, Channel<DuplexStream, B, A>) {
+    let (a, b) = tokio::io::duplex(64 * 1024);
+    (
+        channel(length_delimited(a, limits.max_frame_length), format, limits),
+        channel(length_delimited(b, limits.max_frame_length), format, limits),
+    )
 }
 
-fn channel<IO: AsyncWrite + AsyncRead, Source, Sink>(io: IO) -> Channel<IO, Source, Sink> {
-    tokio_serde::Framed::new(length_delimited(io), Bincode::default())
+/// Read the format announced by the server
+async fn client_handshake<IO: AsyncWrite + AsyncRead + Unpin>(framed: &mut LengthDelimited<IO>) -> io::Result<Format> {
+    let frame = framed.next().await
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Server disconnected during handshake"))??;
+    std::str::from_utf8(&frame).ok()
+        .and_then(Format::from_name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown message format"))
 }
 
-pub fn server_create(pipe_name: &str) -> io::Result<NamedPipeServer> {
-    ServerOptions::new()
-        .first_pipe_instance(true)
-        // .pipe_mode(named_pipe::PipeMode::Message)
-        .create(pipe_name)
+pub fn server_create(pipe_name: &str) -> io::Result<Listener> {
+    transport::server_create(pipe_name)
 }
 
-async fn client_connect<Source, Sink>(pipe_name: &str, timeout: Duration) -> io::Result<Channel<NamedPipeClient, Source, Sink>> {
+async fn client_connect<Source, Sink>(pipe_name: &str, timeout: Duration, limits: Limits) -> io::Result<Channel<ClientIo, Source, Sink>> {
     let poll_period = Duration::from_millis(50);
     tokio::time::timeout(timeout, async {
-        loop {
+        let io = loop {
             tokio::time::sleep(poll_period).await;
-            match client_open(pipe_name) {
-                Ok(client) => break Ok(client),
-                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32) => (),
-                Err(e) => break Err(e),
+            match transport::client_open(pipe_name).await {
+                Ok(client) => break client,
+                Err(e) if transport::is_busy(&e) => (),
+                Err(e) => return Err(e),
             };
-        }
+        };
+        let mut framed = length_delimited(io, limits.max_frame_length);
+        let format = client_handshake(&mut framed).await?;
+        log::debug!("Using {} message format", format.name());
+        Ok(channel(framed, format, limits))
     }).await?
-        .map(channel)
 }
 
-fn client_open(pipe_name: &str) -> io::Result<NamedPipeClient> {
-    ClientOptions::new()
-        // .pipe_mode(named_pipe::PipeMode::Message)
-        .open(pipe_name)
+#[cfg(windows)]
+mod transport {
+    use std::io;
+
+    use windows::Win32::Foundation::ERROR_PIPE_BUSY;
+    use tokio::net::windows::named_pipe::{NamedPipeServer, NamedPipeClient, ServerOptions, ClientOptions};
+
+    pub type ServerIo = NamedPipeServer;
+    pub type ClientIo = NamedPipeClient;
+
+    /// Named pipe waiting for a client connection
+    pub struct Listener(NamedPipeServer);
+
+    impl Listener {
+        pub async fn accept(self) -> io::Result<ServerIo> {
+            self.0.connect().await?;
+            Ok(self.0)
+        }
+    }
+
+    pub fn server_create(pipe_name: &str) -> io::Result<Listener> {
+        ServerOptions::new()
+            .first_pipe_instance(true)
+            // .pipe_mode(named_pipe::PipeMode::Message)
+            .create(pipe_name)
+            .map(Listener)
+    }
+
+    pub async fn client_open(pipe_name: &str) -> io::Result<ClientIo> {
+        ClientOptions::new()
+            // .pipe_mode(named_pipe::PipeMode::Message)
+            .open(pipe_name)
+    }
+
+    pub fn is_busy(err: &io::Error) -> bool {
+        err.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32)
+    }
+}
+
+#[cfg(unix)]
+mod transport {
+    use std::io;
+    use std::fs;
+    use std::os::unix::fs::FileTypeExt;
+    use std::path::PathBuf;
+
+    use tokio::net::{UnixListener, UnixStream};
+
+    pub type ServerIo = UnixStream;
+    pub type ClientIo = UnixStream;
+
+    /// Unix domain socket waiting for a client connection, removes the socket file on drop
+    pub struct Listener {
+        inner: UnixListener,
+        path: PathBuf,
+    }
+
+    impl Listener {
+        pub async fn accept(self) -> io::Result<ServerIo> {
+            let (stream, _addr) = self.inner.accept().await?;
+            Ok(stream)
+        }
+    }
+
+    impl Drop for Listener {
+        fn drop(&mut self) {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+
+    pub fn server_create(pipe_name: &str) -> io::Result<Listener> {
+        let path = PathBuf::from(pipe_name);
+        // Remove a stale socket left by a process that did not exit cleanly, but never
+        // remove anything that is not a socket
+        if let Ok(meta) = fs::symlink_metadata(&path) {
+            if meta.file_type().is_socket() {
+                fs::remove_file(&path)?;
+            }
+        }
+        let inner = UnixListener::bind(&path)?;
+        Ok(Listener { inner, path })
+    }
+
+    pub async fn client_open(pipe_name: &str) -> io::Result<ClientIo> {
+        UnixStream::connect(pipe_name).await
+    }
+
+    pub fn is_busy(err: &io::Error) -> bool {
+        err.kind() == io::ErrorKind::ConnectionRefused
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [Format; 2] = [Format::Bincode, Format::Json];
+
+    fn limits(max_frame_length: usize, max_message_size: u64) -> Limits {
+        Limits { max_frame_length, max_message_size }
+    }
+
+    #[tokio::test]
+    async fn oversize_frame() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let mut sender = length_delimited(a, 1024);
+        let mut receiver: Channel<_, Vec<u8>, Vec<u8>> = channel(length_delimited(b, 64), Format::Bincode, limits(64, 1024));
+        sender.send(Bytes::from(vec![0; 65])).await.unwrap();
+        let err = receiver.next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Sending is limited too
+        let (mut a, _b) = duplex::<Vec<u8>, Vec<u8>>(Format::Bincode, limits(64, 1024));
+        assert!(a.send(vec![0; 100]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn message_size_limit() {
+        for format in FORMATS {
+            let (mut a, mut b) = duplex::<Vec<u8>, Vec<u8>>(format, limits(64 * 1024, 64));
+            a.send(vec![1; 16]).await.unwrap();
+            assert_eq!(b.next().await.unwrap().unwrap(), vec![1; 16]);
+            a.send(vec![1; 100]).await.unwrap();
+            let err = b.next().await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn huge_length_prefix() {
+        // A small frame claiming a huge sequence must not allocate it
+        let data = u64::MAX.to_le_bytes();
+        assert!(decode::<Vec<u8>>(&data, Format::Bincode, 1024).is_err());
+        let data = [&(1u64 << 40).to_le_bytes()[..], &[0; 8]].concat();
+        assert!(decode::<String>(&data, Format::Bincode, 1024).is_err());
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut data = bincode_options(u64::MAX).serialize(&5u32).unwrap();
+        assert_eq!(decode::<u32>(&data, Format::Bincode, 1024).unwrap(), 5);
+        data.push(0);
+        let err = decode::<u32>(&data, Format::Bincode, 1024).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        assert_eq!(decode::<u32>(b"5 ", Format::Json, 1024).unwrap(), 5);
+        assert!(decode::<u32>(b"5 6", Format::Json, 1024).is_err());
+        assert!(decode::<u32>(b"5}", Format::Json, 1024).is_err());
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Msg {
+        Empty,
+        Data { name: String, values: Vec<u16>, extra: Option<Box<Msg>> },
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let msgs = [
+            Msg::Empty,
+            Msg::Data { name: "ünïcode \"quoted\"\n".to_string(), values: vec![0, 1, u16::MAX], extra: None },
+            Msg::Data { name: String::new(), values: Vec::new(), extra: Some(Box::new(Msg::Empty)) },
+        ];
+        for format in FORMATS {
+            let (mut a, mut b) = duplex::<Msg, Msg>(format, Limits::default());
+            for msg in &msgs {
+                a.send(msg.clone()).await.unwrap();
+                assert_eq!(&b.next().await.unwrap().unwrap(), msg, "{:?}", format);
+                b.send(msg.clone()).await.unwrap();
+                assert_eq!(&a.next().await.unwrap().unwrap(), msg, "{:?}", format);
+            }
+        }
+    }
+
+    /// Run the client handshake after the server sent `frames` and closed the connection
+    async fn handshake(frames: &[&[u8]]) -> io::Result<Format> {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut server = length_delimited(a, 1024);
+        for frame in frames {
+            server.send(Bytes::copy_from_slice(frame)).await.unwrap();
+        }
+        drop(server);
+        client_handshake(&mut length_delimited(b, 1024)).await
+    }
+
+    #[tokio::test]
+    async fn format_announcement() {
+        for format in FORMATS {
+            assert_eq!(handshake(&[format.name().as_bytes()]).await.unwrap(), format);
+        }
+        assert_eq!(handshake(&[b" JSON\n"]).await.unwrap(), Format::Json);
+        for frame in [&b""[..], b"msgpack", b"bincode2", b"\xff\xfe", b"\0json"] {
+            let err = handshake(&[frame]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", frame);
+        }
+        let err = handshake(&[]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn format_names() {
+        for format in FORMATS {
+            assert_eq!(Format::from_name(format.name()), Some(format));
+        }
+        assert_eq!(Format::from_name("Bincode"), Some(Format::Bincode));
+        assert_eq!(Format::from_name("yaml"), None);
+    }
 }
@@ -0,0 +1,806 @@
+//! Request/response layer on top of a message [`Channel`]
+//!
+//! The caller sends requests with unique IDs and the responder answers each of them with any
+//! number of streamed items followed by a single response with the same ID. Requests are
+//! handled concurrently and each call has its own timeout, after which the request is
+//! cancelled. Both sides send heartbeats every [`HEARTBEAT_INTERVAL`], so a peer that stopped
+//! responding is detected even when no request is in flight.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use futures::stream::FuturesUnordered;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+
+use super::Channel;
+
+/// How often both sides send heartbeats
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type RequestId = u64;
+
+/// Types of the messages exchanged by the caller and the responder
+pub trait Service: 'static {
+    /// Requests sent by the caller
+    type Request: fmt::Debug + Serialize + DeserializeOwned + Send + Unpin;
+    /// Partial results streamed before the response
+    type Item: fmt::Debug + Serialize + DeserializeOwned + Send + Unpin;
+    /// Final result of a request
+    type Response: fmt::Debug + Serialize + DeserializeOwned + Send + Unpin;
+    /// Failure reported by the responder right before it disconnects
+    type Abort: fmt::Debug + Clone + Serialize + DeserializeOwned + Send + Unpin;
+}
+
+/// Typed request of a [`Service`]
+///
+/// Converts into the service request and extracts the items and the response expected for
+/// this request. Values for which these return `None` are treated as protocol errors.
+pub trait Method<S: Service> {
+    /// Items streamed while handling the request, [`Infallible`] if there are none
+    type Item;
+    /// Value of the final response
+    type Output;
+
+    fn into_request(self) -> S::Request;
+
+    fn item(item: S::Item) -> Option<Self::Item>;
+
+    fn output(response: S::Response) -> Option<Self::Output>;
+}
+
+/// Messages sent by the caller
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CallerMsg<Request> {
+    Request { id: RequestId, body: Request },
+    /// Caller is no longer interested in the result of the request
+    Cancel { id: RequestId },
+    Heartbeat,
+    /// Responder should finish after handling the requests in flight
+    Close,
+}
+
+/// Messages sent by the responder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ResponderMsg<Item, Response, Abort> {
+    Item { id: RequestId, body: Item },
+    /// Final result of the request, `Err` if it could not be handled
+    Response { id: RequestId, result: Result<Response, String> },
+    Heartbeat,
+    /// Responder failed and is about to disconnect
+    Abort(Abort),
+}
+
+pub type CallerMsgOf<S> = CallerMsg<<S as Service>::Request>;
+pub type ResponderMsgOf<S> = ResponderMsg<<S as Service>::Item, <S as Service>::Response, <S as Service>::Abort>;
+
+/// Channel used by the caller, receiving [`ResponderMsg`]s
+pub type CallerChannel<S, IO> = Channel<IO, ResponderMsgOf<S>, CallerMsgOf<S>>;
+/// Channel used by the responder, receiving [`CallerMsg`]s
+pub type ResponderChannel<S, IO> = Channel<IO, CallerMsgOf<S>, ResponderMsgOf<S>>;
+
+/// Error of a single call
+#[derive(Debug)]
+pub enum CallError<A> {
+    /// Communication failed, the call timed out or the responder stopped sending heartbeats
+    Io(io::Error),
+    /// Responder could not handle the request
+    Failed(String),
+    /// Responder failed and disconnected
+    Aborted(A),
+}
+
+impl<A: fmt::Display> fmt::Display for CallError<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{}", err),
+            Self::Failed(err) => write!(f, "Request failed: {}", err),
+            Self::Aborted(abort) => write!(f, "Responder aborted: {}", abort),
+        }
+    }
+}
+
+impl<A> From<io::Error> for CallError<A> {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Reason why the connection ended, read by the calls that were still in flight
+enum Disconnect<A> {
+    Io(io::ErrorKind, String),
+    Aborted(A),
+}
+
+impl<A: Clone> Disconnect<A> {
+    fn error(&self) -> CallError<A> {
+        match self {
+            Self::Io(kind, message) => CallError::Io(io::Error::new(*kind, message.clone())),
+            Self::Aborted(abort) => CallError::Aborted(abort.clone()),
+        }
+    }
+}
+
+impl<A> From<io::Error> for Disconnect<A> {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err.kind(), err.to_string())
+    }
+}
+
+type SharedDisconnect<A> = Arc<Mutex<Option<Disconnect<A>>>>;
+
+enum Command<S: Service> {
+    Call { id: RequestId, request: S::Request, replies: mpsc::UnboundedSender<Reply<S>> },
+    Cancel(RequestId),
+    Close(oneshot::Sender<()>),
+}
+
+enum Reply<S: Service> {
+    Item(S::Item),
+    Response(Result<S::Response, String>),
+}
+
+/// Caller side of a connection
+///
+/// The connection is handled by a spawned task, calls only take `&self` so multiple calls can
+/// be in flight at the same time.
+pub struct Caller<S: Service> {
+    commands: mpsc::UnboundedSender<Command<S>>,
+    next_id: AtomicU64,
+    disconnect: SharedDisconnect<S::Abort>,
+}
+
+impl<S: Service> Caller<S> {
+    /// Start handling the connection on a new task
+    ///
+    /// All calls fail if nothing is received from the responder for `keepalive_timeout`.
+    pub fn spawn<IO>(channel: CallerChannel<S, IO>, keepalive_timeout: Duration) -> Self
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (commands, rx) = mpsc::unbounded_channel();
+        let disconnect = Arc::new(Mutex::new(None));
+        tokio::spawn(run_caller(channel, rx, keepalive_timeout, disconnect.clone()));
+        Self {
+            commands,
+            next_id: AtomicU64::new(1),
+            disconnect,
+        }
+    }
+
+    /// Send a request, the returned [`Call`] receives the streamed items and the response
+    ///
+    /// The request is cancelled if it does not finish within `timeout` or when the call gets
+    /// dropped before the response.
+    pub fn call<M: Method<S>>(&self, method: M, timeout: Duration) -> Call<S, M> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (replies, rx) = mpsc::unbounded_channel();
+        // If the connection is gone the call fails on the first read
+        self.commands.send(Command::Call { id, request: method.into_request(), replies }).ok();
+        Call {
+            id,
+            replies: rx,
+            deadline: tokio::time::Instant::now() + timeout,
+            commands: self.commands.clone(),
+            disconnect: self.disconnect.clone(),
+            finished: false,
+            _method: PhantomData,
+        }
+    }
+
+    /// Ask the responder to finish and wait until the request is sent
+    pub async fn close(self) {
+        let (done, rx) = oneshot::channel();
+        if self.commands.send(Command::Close(done)).is_ok() {
+            rx.await.ok();
+        }
+    }
+}
+
+/// Handle the connection until it fails, gets closed or all callers are gone
+async fn run_caller<S: Service, IO: AsyncRead + AsyncWrite + Unpin>(
+    mut channel: CallerChannel<S, IO>,
+    mut commands: mpsc::UnboundedReceiver<Command<S>>,
+    keepalive_timeout: Duration,
+    disconnect: SharedDisconnect<S::Abort>,
+) {
+    // Dropped after setting the disconnect reason, which makes the pending calls read it
+    let mut pending: HashMap<RequestId, mpsc::UnboundedSender<Reply<S>>> = HashMap::new();
+    let mut last_received = Instant::now();
+    let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+    let reason = loop {
+        let result = tokio::select! {
+            // Register queued calls first, a response must never be read before its request
+            biased;
+            command = commands.recv() => match command {
+                Some(Command::Call { id, request, replies }) => {
+                    log::trace!("Calling request {}: {:?}", id, request);
+                    pending.insert(id, replies);
+                    channel.send(CallerMsg::Request { id, body: request }).await
+                },
+                Some(Command::Cancel(id)) => match pending.remove(&id) {
+                    Some(_) => channel.send(CallerMsg::Cancel { id }).await,
+                    None => Ok(()),
+                },
+                Some(Command::Close(done)) => {
+                    let result = channel.send(CallerMsg::Close).await;
+                    done.send(()).ok();
+                    break result.map_or_else(Disconnect::from,
+                        |()| Disconnect::Io(io::ErrorKind::NotConnected, "Connection closed".to_string()));
+                },
+                None => {
+                    channel.send(CallerMsg::Close).await.ok();
+                    break Disconnect::Io(io::ErrorKind::NotConnected, "Connection closed".to_string());
+                },
+            },
+            msg = channel.next() => {
+                last_received = Instant::now();
+                match msg {
+                    Some(Ok(ResponderMsg::Item { id, body })) => {
+                        match pending.get(&id) {
+                            Some(replies) => { replies.send(Reply::Item(body)).ok(); },
+                            None => log::debug!("Ignoring item of request {}: {:?}", id, body),
+                        }
+                        Ok(())
+                    },
+                    Some(Ok(ResponderMsg::Response { id, result })) => {
+                        match pending.remove(&id) {
+                            Some(replies) => { replies.send(Reply::Response(result)).ok(); },
+                            None => log::debug!("Ignoring response to request {}: {:?}", id, result),
+                        }
+                        Ok(())
+                    },
+                    Some(Ok(ResponderMsg::Heartbeat)) => Ok(()),
+                    Some(Ok(ResponderMsg::Abort(abort))) => break Disconnect::Aborted(abort),
+                    Some(Err(err)) => Err(err),
+                    None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Responder disconnected")),
+                }
+            },
+            _ = ticker.tick() => {
+                if last_received.elapsed() > keepalive_timeout {
+                    Err(io::Error::new(io::ErrorKind::TimedOut,
+                        format!("No heartbeat for {:?}", last_received.elapsed())))
+                } else {
+                    channel.send(CallerMsg::Heartbeat).await
+                }
+            },
+        };
+        if let Err(err) = result {
+            break err.into();
+        }
+    };
+
+    if let Disconnect::Io(_, message) = &reason {
+        if !pending.is_empty() {
+            log::error!("Connection failed with {} requests in flight: {}", pending.len(), message);
+        }
+    }
+    *disconnect.lock().unwrap_or_else(|e| e.into_inner()) = Some(reason);
+}
+
+/// Update of a call, see [`Call::next`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Update<Item, Output> {
+    Item(Item),
+    Done(Output),
+}
+
+/// Request in flight
+pub struct Call<S: Service, M: Method<S>> {
+    id: RequestId,
+    replies: mpsc::UnboundedReceiver<Reply<S>>,
+    deadline: tokio::time::Instant,
+    commands: mpsc::UnboundedSender<Command<S>>,
+    disconnect: SharedDisconnect<S::Abort>,
+    finished: bool,
+    _method: PhantomData<M>,
+}
+
+impl<S: Service, M: Method<S>> Call<S, M> {
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    /// Wait for the next item or the final response
+    ///
+    /// Must not be called again after it returned the response or an error.
+    pub async fn next(&mut self) -> Result<Update<M::Item, M::Output>, CallError<S::Abort>> {
+        let reply = match tokio::time::timeout_at(self.deadline, self.replies.recv()).await {
+            Ok(Some(reply)) => reply,
+            Ok(None) => {
+                self.finished = true;
+                return Err(self.disconnect_error());
+            },
+            Err(_) => {
+                self.cancel();
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                    format!("Request {} timed out", self.id)).into());
+            },
+        };
+        match reply {
+            Reply::Item(item) => M::item(item)
+                .map(Update::Item)
+                .ok_or_else(|| self.unexpected("item")),
+            Reply::Response(result) => {
+                self.finished = true;
+                let response = result.map_err(CallError::Failed)?;
+                M::output(response)
+                    .map(Update::Done)
+                    .ok_or_else(|| self.unexpected("response"))
+            },
+        }
+    }
+
+    /// Wait for the response, passing the streamed items to `on_item`
+    pub async fn response_with(mut self, mut on_item: impl FnMut(M::Item)) -> Result<M::Output, CallError<S::Abort>> {
+        loop {
+            match self.next().await? {
+                Update::Item(item) => on_item(item),
+                Update::Done(output) => return Ok(output),
+            }
+        }
+    }
+
+    /// Wait for the response of a request that does not stream any items
+    pub async fn response(self) -> Result<M::Output, CallError<S::Abort>>
+    where
+        M: Method<S, Item = Infallible>,
+    {
+        self.response_with(|never| match never {}).await
+    }
+
+    fn cancel(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.commands.send(Command::Cancel(self.id)).ok();
+        }
+    }
+
+    fn unexpected(&mut self, what: &str) -> CallError<S::Abort> {
+        self.cancel();
+        io::Error::new(io::ErrorKind::InvalidData,
+            format!("Unexpected {} for request {}", what, self.id)).into()
+    }
+
+    fn disconnect_error(&self) -> CallError<S::Abort> {
+        match &*self.disconnect.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(disconnect) => disconnect.error(),
+            None => io::Error::new(io::ErrorKind::NotConnected, "Connection closed").into(),
+        }
+    }
+}
+
+impl<S: Service, M: Method<S>> Drop for Call<S, M> {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// State of the responder shared with the request handlers
+struct Shared {
+    last_received: Mutex<Instant>,
+    keepalive_timeout: Mutex<Option<Duration>>,
+}
+
+/// Request being handled by the responder
+///
+/// Can be cloned and moved to other threads, e.g. to stream items from blocking code.
+pub struct Context<S: Service> {
+    id: RequestId,
+    out: mpsc::UnboundedSender<ResponderMsgOf<S>>,
+    cancelled: Arc<AtomicBool>,
+    shared: Arc<Shared>,
+}
+
+impl<S: Service> Clone for Context<S> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            out: self.out.clone(),
+            cancelled: self.cancelled.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<S: Service> Context<S> {
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    /// Stream an item to the caller
+    pub fn send_item(&self, item: S::Item) {
+        self.out.send(ResponderMsg::Item { id: self.id, body: item }).ok();
+    }
+
+    /// Check if the request has been cancelled by the caller or because the connection ends
+    ///
+    /// Response to a cancelled request is not sent, handlers should stop as soon as possible.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Time since the last message received from the caller
+    pub fn since_last_message(&self) -> Duration {
+        self.shared.last_received.lock().unwrap_or_else(|e| e.into_inner()).elapsed()
+    }
+
+    /// End the connection when nothing is received from the caller for `timeout`, `None` disables
+    /// the check (default)
+    ///
+    /// This applies to the whole connection, not only this request.
+    pub fn keepalive_timeout(&self, timeout: Option<Duration>) {
+        *self.shared.keepalive_timeout.lock().unwrap_or_else(|e| e.into_inner()) = timeout;
+    }
+}
+
+/// Handle requests until the caller closes the connection
+///
+/// Each request is handled by the future returned from `handler`, these run concurrently.
+/// When `stop` resolves, the caller disconnects or the keepalive timeout set by
+/// [`Context::keepalive_timeout`] passes, all requests in flight get cancelled and the function
+/// returns once their handlers finish. Nothing is written to the channel after it failed. The
+/// caller disconnecting while no requests are in flight is not an error.
+pub async fn serve<S, IO, H, F>(
+    channel: &mut ResponderChannel<S, IO>,
+    handler: H,
+    stop: impl Future<Output = io::Error>,
+) -> io::Result<()>
+where
+    S: Service,
+    IO: AsyncRead + AsyncWrite + Unpin,
+    H: Fn(S::Request, Context<S>) -> F,
+    F: Future<Output = Result<S::Response, String>>,
+{
+    let shared = Arc::new(Shared {
+        last_received: Mutex::new(Instant::now()),
+        keepalive_timeout: Mutex::new(None),
+    });
+    let (out, mut out_rx) = mpsc::unbounded_channel();
+    let mut running = FuturesUnordered::new();
+    let mut cancels: HashMap<RequestId, Arc<AtomicBool>> = HashMap::new();
+    let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+    // Result returned after the requests in flight finish
+    let mut closing: Option<io::Result<()>> = None;
+    futures::pin_mut!(stop);
+
+    let cancel_all = |cancels: &HashMap<RequestId, Arc<AtomicBool>>, reason: &dyn fmt::Display| {
+        if !cancels.is_empty() {
+            log::error!("Cancelling {} requests: {}", cancels.len(), reason);
+        }
+        for cancelled in cancels.values() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    };
+
+    loop {
+        // Set once the connection failed, the messages of the remaining handlers are dropped
+        let failed = matches!(closing, Some(Err(_)));
+        if closing.is_some() && running.is_empty() {
+            if failed {
+                return closing.take().unwrap();
+            }
+            // Send the responses of the last requests
+            let mut flushed = Ok(());
+            while let Ok(msg) = out_rx.try_recv() {
+                flushed = flushed.and(channel.feed(msg).await);
+            }
+            return flushed.and(channel.flush().await);
+        }
+
+        let result = tokio::select! {
+            msg = channel.next(), if closing.is_none() => {
+                *shared.last_received.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+                match msg.transpose() {
+                    Ok(Some(CallerMsg::Request { id, body })) => {
+                        log::trace!("Handling request {}: {:?}", id, body);
+                        let cancelled = Arc::new(AtomicBool::new(false));
+                        cancels.insert(id, cancelled.clone());
+                        let context = Context { id, out: out.clone(), cancelled, shared: shared.clone() };
+                        let handling = handler(body, context);
+                        running.push(async move { (id, handling.await) });
+                        Ok(())
+                    },
+                    Ok(Some(CallerMsg::Cancel { id })) => {
+                        if let Some(cancelled) = cancels.get(&id) {
+                            log::warn!("Request {} cancelled by the caller", id);
+                            cancelled.store(true, Ordering::Relaxed);
+                        }
+                        Ok(())
+                    },
+                    Ok(Some(CallerMsg::Heartbeat)) => Ok(()),
+                    Ok(Some(CallerMsg::Close)) => {
+                        log::debug!("Caller closed the connection");
+                        closing = Some(Ok(()));
+                        Ok(())
+                    },
+                    // Connection closed without Close, e.g. the caller crashed
+                    Ok(None) if cancels.is_empty() => {
+                        log::warn!("Caller disconnected");
+                        closing = Some(Ok(()));
+                        Ok(())
+                    },
+                    Ok(None) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Caller disconnected")),
+                    Err(err) => Err(err),
+                }
+            },
+            Some((id, result)) = running.next(), if !running.is_empty() => {
+                let cancelled = cancels.remove(&id).is_some_and(|cancelled| cancelled.load(Ordering::Relaxed));
+                if cancelled {
+                    log::debug!("Dropping response to cancelled request {}: {:?}", id, result);
+                } else {
+                    // Queued after the items of this request, so it cannot overtake them
+                    out.send(ResponderMsg::Response { id, result }).ok();
+                }
+                Ok(())
+            },
+            Some(msg) = out_rx.recv() => if failed {
+                log::trace!("Dropping message after the connection failed: {:?}", msg);
+                Ok(())
+            } else {
+                let sent = channel.send(msg).await;
+                if closed_between_requests(&sent, &cancels) {
+                    return Ok(());
+                }
+                sent
+            },
+            _ = ticker.tick(), if !failed => {
+                let timeout = *shared.keepalive_timeout.lock().unwrap_or_else(|e| e.into_inner());
+                let elapsed = shared.last_received.lock().unwrap_or_else(|e| e.into_inner()).elapsed();
+                match timeout {
+                    Some(timeout) if closing.is_none() && elapsed > timeout => {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, format!("No heartbeat from caller for {:?}", elapsed)))
+                    },
+                    _ => {
+                        let sent = channel.send(ResponderMsg::Heartbeat).await;
+                        if closed_between_requests(&sent, &cancels) {
+                            return Ok(());
+                        }
+                        sent
+                    },
+                }
+            },
+            err = &mut stop, if closing.is_none() => Err(err),
+        };
+
+        if let Err(err) = result {
+            // Handlers still get to finish, they may hold resources that must be released
+            cancel_all(&cancels, &err);
+            closing = Some(Err(err));
+        }
+    }
+}
+
+/// Check if sending failed because the caller closed the connection while no requests were
+/// in flight, e.g. a heartbeat sent before reading the Close message of the caller
+fn closed_between_requests(sent: &io::Result<()>, in_flight: &HashMap<RequestId, Arc<AtomicBool>>) -> bool {
+    let closed = sent.as_ref().is_err_and(|err| matches!(err.kind(),
+        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted));
+    if closed && in_flight.is_empty() {
+        log::warn!("Caller disconnected");
+    }
+    closed && in_flight.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::{duplex, Format, Limits};
+
+    struct Test;
+
+    impl Service for Test {
+        type Request = Work;
+        type Item = u32;
+        type Response = u32;
+        type Abort = String;
+    }
+
+    /// Streams `items` numbers, then responds with `value` after `delay_ms` unless cancelled
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Work {
+        value: u32,
+        items: u32,
+        delay_ms: u64,
+    }
+
+    impl Method<Test> for Work {
+        type Item = u32;
+        type Output = u32;
+
+        fn into_request(self) -> Work {
+            self
+        }
+
+        fn item(item: u32) -> Option<u32> {
+            Some(item)
+        }
+
+        fn output(response: u32) -> Option<u32> {
+            Some(response)
+        }
+    }
+
+    fn work(value: u32, items: u32, delay_ms: u64) -> Work {
+        Work { value, items, delay_ms }
+    }
+
+    async fn handle(request: Work, ctx: Context<Test>) -> Result<u32, String> {
+        for item in 0..request.items {
+            ctx.send_item(item);
+        }
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(request.delay_ms);
+        while tokio::time::Instant::now() < deadline {
+            if ctx.is_cancelled() {
+                return Err("Cancelled".to_string());
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        Ok(request.value)
+    }
+
+    fn channels() -> (CallerChannel<Test, tokio::io::DuplexStream>, ResponderChannel<Test, tokio::io::DuplexStream>) {
+        duplex(Format::default(), Limits::default())
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn concurrent_calls() {
+        let (caller, mut responder) = channels();
+        let caller = Caller::<Test>::spawn(caller, TIMEOUT);
+        let calls = async {
+            let slow = caller.call(work(1, 0, 300), TIMEOUT);
+            let fast = caller.call(work(2, 0, 0), TIMEOUT);
+            assert_ne!(slow.id(), fast.id());
+            // The fast request is answered while the slow one is still being handled
+            let fast = tokio::time::timeout(Duration::from_millis(200), fast.response_with(|_| {})).await;
+            assert_eq!(fast.expect("Requests are not handled concurrently").unwrap(), 2);
+            assert_eq!(slow.response_with(|_| {}).await.unwrap(), 1);
+            caller.close().await;
+        };
+        let (served, ()) = future::join(serve(&mut responder, handle, future::pending()), calls).await;
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn timeout_cancels_request() {
+        let (caller, mut responder) = channels();
+        let caller = Caller::<Test>::spawn(caller, TIMEOUT);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let handler = |request, ctx: Context<Test>| {
+            let cancelled = cancelled.clone();
+            async move {
+                let result = handle(request, ctx.clone()).await;
+                cancelled.store(ctx.is_cancelled(), Ordering::Relaxed);
+                result
+            }
+        };
+        let calls = async {
+            let call = caller.call(work(1, 0, 5000), Duration::from_millis(50));
+            match call.response_with(|_| {}).await {
+                Err(CallError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+                result => panic!("Unexpected result {:?}", result),
+            }
+            caller.close().await;
+        };
+        let (served, ()) = future::join(serve(&mut responder, handler, future::pending()), calls).await;
+        served.unwrap();
+        assert!(cancelled.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn items_before_response() {
+        let (caller, mut responder) = channels();
+        let caller = Caller::<Test>::spawn(caller, TIMEOUT);
+        let calls = async {
+            let mut items = Vec::new();
+            let call = caller.call(work(7, 3, 0), TIMEOUT);
+            assert_eq!(call.response_with(|item| items.push(item)).await.unwrap(), 7);
+            assert_eq!(items, [0, 1, 2]);
+
+            let mut call = caller.call(work(8, 1, 0), TIMEOUT);
+            assert_eq!(call.next().await.unwrap(), Update::Item(0));
+            assert_eq!(call.next().await.unwrap(), Update::Done(8));
+            caller.close().await;
+        };
+        let (served, ()) = future::join(serve(&mut responder, handle, future::pending()), calls).await;
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn abort_fails_calls_in_flight() {
+        let (caller, mut responder) = channels();
+        let caller = Caller::<Test>::spawn(caller, TIMEOUT);
+        let first = caller.call(work(1, 0, 0), TIMEOUT);
+        let second = caller.call(work(2, 0, 0), TIMEOUT);
+
+        for _ in 0..2 {
+            match responder.next().await {
+                Some(Ok(CallerMsg::Request { .. })) => {},
+                msg => panic!("Unexpected message {:?}", msg),
+            }
+        }
+        responder.send(ResponderMsg::Abort("Fatal".to_string())).await.unwrap();
+
+        for call in [first, second] {
+            match call.response_with(|_| {}).await {
+                Err(CallError::Aborted(abort)) => assert_eq!(abort, "Fatal"),
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+        // Calls made after the abort fail the same way
+        match caller.call(work(3, 0, 0), TIMEOUT).response_with(|_| {}).await {
+            Err(CallError::Aborted(abort)) => assert_eq!(abort, "Fatal"),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn close_waits_for_requests_in_flight() {
+        let (mut caller, mut responder) = channels();
+        let calls = async {
+            caller.send(CallerMsg::Request { id: 1, body: work(5, 1, 100) }).await.unwrap();
+            caller.send(CallerMsg::Close).await.unwrap();
+        };
+        let (served, ()) = future::join(serve(&mut responder, handle, future::pending()), calls).await;
+        served.unwrap();
+        drop(responder);
+
+        let mut received = Vec::new();
+        while let Some(msg) = caller.next().await {
+            match msg.unwrap() {
+                ResponderMsg::Heartbeat => {},
+                msg => received.push(msg),
+            }
+        }
+        assert!(matches!(received[..], [
+            ResponderMsg::Item { id: 1, body: 0 },
+            ResponderMsg::Response { id: 1, result: Ok(5) },
+        ]), "{:?}", received);
+    }
+
+    #[tokio::test]
+    async fn failed_connection_waits_for_handlers() {
+        let (mut caller, mut responder) = channels();
+        let finished = Arc::new(AtomicBool::new(false));
+        let handler = |request, ctx: Context<Test>| {
+            let finished = finished.clone();
+            async move {
+                // Keeps running after the cancellation and tries to send more items
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let result = handle(request, ctx).await;
+                finished.store(true, Ordering::Relaxed);
+                result
+            }
+        };
+        caller.send(CallerMsg::Request { id: 1, body: work(5, 3, 0) }).await.unwrap();
+        drop(caller);
+
+        let err = serve(&mut responder, handler, future::pending()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(finished.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn disconnect_between_requests() {
+        let broken = || Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        let mut in_flight = HashMap::new();
+        assert!(closed_between_requests(&broken(), &in_flight));
+        assert!(closed_between_requests(&Err(io::Error::from(io::ErrorKind::ConnectionReset)), &in_flight));
+        assert!(!closed_between_requests(&Ok(()), &in_flight));
+        assert!(!closed_between_requests(&Err(io::Error::from(io::ErrorKind::InvalidData)), &in_flight));
+        // Responses of the requests in flight would be lost
+        in_flight.insert(1, Arc::new(AtomicBool::new(false)));
+        assert!(!closed_between_requests(&broken(), &in_flight));
+    }
+}
@@ -4,65 +4,346 @@
 //! process with elevated permissions and communicating via IPC to perform the installation
 //! process.
 //!
-//! The [`Server`] is started in the parent (non-privileged) process. It then uses Windows "runas"
-//! command to spawn the client executable (by default the same executable). Client executable's
-//! job is to create and run [`Client`]. It is assumed that client/server are identified by the
-//! number of process arguments - server has no arguments and client receives a single argument
-//! which indicates the name of Windows pipe used for IPC.
+//! The [`Server`] is started in the parent (non-privileged) process. It then uses an
+//! [`elevate::Elevator`] (by default Windows "runas" command) to spawn the client executable
+//! (by default the same executable). Client executable's job is to create and run [`Client`].
+//! It is assumed that client/server are identified by the process arguments - server has no
+//! arguments and client receives the name of Windows pipe used for IPC, optionally followed by
+//! `--parent-pid=<PID>` of the server, which the client watches to exit when orphaned.
+//!
+//! On Linux the same API installs udev rules granting user access to the devices (see [`udev`]),
+//! the client is spawned using `pkexec` and communicates over a Unix domain socket.
+//!
+//! Besides driver installation, applications can run their own privileged operations in the
+//! client, see [`Operation`].
 
 use std::{io, env};
-use std::ffi::OsStr;
+use std::any::Any;
+use std::convert::Infallible;
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
+use std::sync::{Mutex, Once};
 use std::time::{Duration, Instant};
 
 use futures::prelude::*;
 use serde::{Serialize, Deserialize};
 
+mod device;
+pub mod elevate;
+mod error;
+mod install;
+mod operation;
+mod policy;
 pub mod ipc;
+pub mod replay;
+#[cfg(windows)]
 pub mod runas;
+#[cfg(target_os = "linux")]
+pub mod udev;
+#[cfg(windows)]
 pub mod winusb;
 
-use ipc::{Protocol, ProtocolTypes};
-use tokio::sync::{oneshot, mpsc};
+#[cfg(not(any(windows, target_os = "linux")))]
+compile_error!("Only Windows and Linux are supported");
+
+#[cfg(target_os = "linux")]
+use udev as backend;
+#[cfg(windows)]
+use winusb as backend;
+
+use elevate::{ClientCommand, ClientProcess, Elevator, InProcessConfig};
+use ipc::{rpc, Protocol};
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(windows)]
+use tokio::sync::oneshot;
 
-pub use winusb::{Device, InstallConfig};
+pub use error::{ClientFatal, Error};
+pub use operation::{Operation, Operations};
+pub use policy::{DeviceMatcher, PolicyViolation, SecurityPolicy};
+pub use install::{
+    DeviceReport, ErrorCode, InstallError, InstallOptions, InstallOutcome, InstallPolicy, InstallReport,
+    RetryPolicy, VerifyOptions,
+};
+pub use device::{
+    ConfigError, Device, DeviceChange, DeviceField, DeviceFilter, DeviceKey, DeviceSnapshot,
+    DriverPackage, DriverSupport, DriverType, DriverVersion, InstallConfig, SnapshotDiff,
+};
 
+/// Requests sent by the server
 #[derive(Debug, Clone, Serialize, Deserialize)]
-enum ServerMsg {
-    /// Request driver installation
-    Install(InstallConfig, Vec<Device>),
+enum Request {
+    /// Check that the client accepted the environment passed on its command line
+    Environment,
+    /// Request driver installation, progress is streamed as [`Progress`] items
+    Install(InstallConfig, Vec<Device>, InstallOptions),
+    /// Request state of devices and drivers without installing anything
+    Query,
+    /// Run an operation registered by the application, see [`Operation`]
+    Operation { name: String, request: String },
     /// Configure logging
+    #[cfg(windows)]
     Logging { window: winusb::Window },
-    /// Request client process to exit
-    Exit,
 }
 
+/// Responses of the client
 #[derive(Debug, Clone, Serialize, Deserialize)]
-enum ClientMsg {
-    /// Result of installing drivers for single device
-    DeviceInstall(Device, Result<(), String>),
-    /// Other error
-    Error(String),
-    /// Installation request handling started
-    InstallStarted,
-    /// Installation request handling done
-    InstallDone,
-    /// Sent during installation to indicate that client is alive
-    Heatbeat,
+enum Response {
+    /// Request has been handled, nothing to return
+    Done,
+    /// Response to [`Request::Query`]
+    Status(DriverStatus),
+    /// Output of [`Request::Operation`]
+    Operation(String),
+    /// Request rejected by the [`SecurityPolicy`] of the client
+    Rejected(PolicyViolation),
+    /// Installation request with configuration that did not pass [`InstallConfig::validate`]
+    InvalidConfig(ConfigError),
+}
+
+type ServerMsg = rpc::CallerMsgOf<Installation>;
+type ClientMsg = rpc::ResponderMsgOf<Installation>;
+
+/// Log failure reported by the client and convert it to an error
+impl From<rpc::CallError<ClientFatal>> for Error {
+    fn from(err: rpc::CallError<ClientFatal>) -> Self {
+        match err {
+            rpc::CallError::Io(err) => Error::Io(err),
+            rpc::CallError::Failed(err) => Error::Client(err),
+            rpc::CallError::Aborted(fatal) => {
+                log::error!("Client failed: {} at {:?}", fatal.message, fatal.location);
+                if let Some(backtrace) = &fatal.backtrace {
+                    log::debug!("Client backtrace:\n{}", backtrace);
+                }
+                Error::ClientFatal(fatal)
+            },
+        }
+    }
 }
 
 struct Installation;
 
+/// Source of devices and drivers, see [`Server::root`] and [`Client::root`]
+#[derive(Debug, Clone, Default)]
+struct Backend {
+    /// Directory used instead of `/` for sysfs and udev rules
+    #[cfg(target_os = "linux")]
+    root: Option<PathBuf>,
+}
+
+impl Backend {
+    fn devices(&self, filter: Box<DeviceFilter>) -> backend::Result<backend::Devices> {
+        #[cfg(target_os = "linux")]
+        if let Some(root) = &self.root {
+            return backend::Devices::with_root(root, filter);
+        }
+        backend::Devices::new(filter)
+    }
+
+    fn visible_devices(&self) -> io::Result<Vec<Device>> {
+        self.devices(Box::new(|_| true))
+            .map_err(io::Error::other)
+            .map(|devices| devices.candidates().collect())
+    }
+
+    fn driver_store(&self) -> io::Result<Vec<DriverPackage>> {
+        #[cfg(target_os = "linux")]
+        if let Some(root) = &self.root {
+            return backend::driver_store_in(root);
+        }
+        backend::driver_store()
+    }
+}
+
+impl rpc::Service for Installation {
+    type Request = Request;
+    type Item = Progress;
+    type Response = Response;
+    type Abort = ClientFatal;
+}
+
+/// Typed requests of the installation protocol
+struct CheckEnvironment;
+struct Install(InstallConfig, Vec<Device>, InstallOptions);
+struct Query;
+struct RunOperation { name: String, request: String }
+#[cfg(windows)]
+struct SetupLogging(winusb::Window);
+
+impl rpc::Method<Installation> for CheckEnvironment {
+    type Item = Infallible;
+    type Output = Result<(), Error>;
+
+    fn into_request(self) -> Request {
+        Request::Environment
+    }
+
+    fn item(_: Progress) -> Option<Infallible> {
+        None
+    }
+
+    fn output(response: Response) -> Option<Result<(), Error>> {
+        match response {
+            Response::Done => Some(Ok(())),
+            Response::Rejected(violation) => Some(Err(Error::PolicyViolation(violation))),
+            _ => None,
+        }
+    }
+}
+
+impl rpc::Method<Installation> for Install {
+    type Item = Progress;
+    type Output = Result<(), Error>;
+
+    fn into_request(self) -> Request {
+        Request::Install(self.0, self.1, self.2)
+    }
+
+    fn item(item: Progress) -> Option<Progress> {
+        Some(item)
+    }
+
+    fn output(response: Response) -> Option<Result<(), Error>> {
+        match response {
+            Response::Done => Some(Ok(())),
+            Response::Rejected(violation) => Some(Err(Error::PolicyViolation(violation))),
+            Response::InvalidConfig(err) => Some(Err(Error::InvalidConfig(err))),
+            _ => None,
+        }
+    }
+}
+
+impl rpc::Method<Installation> for Query {
+    type Item = Infallible;
+    type Output = DriverStatus;
+
+    fn into_request(self) -> Request {
+        Request::Query
+    }
+
+    fn item(_: Progress) -> Option<Infallible> {
+        None
+    }
+
+    fn output(response: Response) -> Option<DriverStatus> {
+        match response {
+            Response::Status(status) => Some(status),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(windows)]
+impl rpc::Method<Installation> for SetupLogging {
+    type Item = Infallible;
+    type Output = ();
+
+    fn into_request(self) -> Request {
+        Request::Logging { window: self.0 }
+    }
+
+    fn item(_: Progress) -> Option<Infallible> {
+        None
+    }
+
+    fn output(response: Response) -> Option<()> {
+        matches!(response, Response::Done).then_some(())
+    }
+}
+
+impl rpc::Method<Installation> for RunOperation {
+    type Item = Infallible;
+    type Output = String;
+
+    fn into_request(self) -> Request {
+        Request::Operation { name: self.name, request: self.request }
+    }
+
+    fn item(_: Progress) -> Option<Infallible> {
+        None
+    }
+
+    fn output(response: Response) -> Option<String> {
+        match response {
+            Response::Operation(output) => Some(output),
+            _ => None,
+        }
+    }
+}
+
+/// Untyped request, used when replaying recordings
+impl rpc::Method<Installation> for Request {
+    type Item = Progress;
+    type Output = Response;
+
+    fn into_request(self) -> Request {
+        self
+    }
+
+    fn item(item: Progress) -> Option<Progress> {
+        Some(item)
+    }
+
+    fn output(response: Response) -> Option<Response> {
+        Some(response)
+    }
+}
+
 impl ipc::Protocol for Installation {
     type ServerMsg = ServerMsg;
     type ClientMsg = ClientMsg;
+
+    /// Requests received by the elevated client are small, keep the limits tight
+    fn server_msg_limits() -> ipc::Limits {
+        ipc::Limits {
+            max_frame_length: 256 * 1024,
+            max_message_size: 256 * 1024,
+        }
+    }
 }
 
+/// Entry points for the fuzz targets in `fuzz/`
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzzing {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, LengthDelimitedCodec};
+
+    use crate::ipc::{self, Protocol};
+
+    /// Decode bytes received by the elevated client the same way [`crate::Client`] does
+    pub fn decode_server_msgs(data: &[u8]) {
+        let limits = crate::Installation::server_msg_limits();
+        let mut codec = LengthDelimitedCodec::builder()
+            .max_frame_length(limits.max_frame_length)
+            .new_codec();
+        let mut buf = BytesMut::from(data);
+        while let Ok(Some(frame)) = codec.decode(&mut buf) {
+            for format in [ipc::Format::Bincode, ipc::Format::Json] {
+                let _ = ipc::decode::<crate::ServerMsg>(&frame, format, limits.max_message_size);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
 fn pipe_name(pipe_id: &str) -> String {
     assert!(!pipe_id.starts_with(r"\\."));
     String::from(r"\\.\pipe\") + pipe_id
 }
 
+#[cfg(unix)]
+fn pipe_name(pipe_id: &str) -> String {
+    assert!(!pipe_id.contains('/'));
+    let dir = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    dir.join(format!("{}.sock", pipe_id)).to_string_lossy().into_owned()
+}
+
+#[allow(clippy::large_enum_variant)]
 pub enum Mode {
     Server(Server),
     Client(Client),
@@ -73,24 +354,90 @@ pub enum Mode {
 /// Depending on program env::args this will resolve either to a server or a client.
 /// Server is the one that spawns the client (with elevated privilege) and initiates
 /// all operations.
+///
+/// In the client process this also installs a panic hook, so that panics are reported to the
+/// server with their location and backtrace.
 pub fn init() -> Mode {
-    match env::args().nth(1) {
-        Some(pipe_name) => Mode::Client(Client::new(pipe_name)),
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    match Client::from_args(&args) {
+        Some(client) => {
+            install_panic_hook();
+            Mode::Client(client)
+        },
         None => Mode::Server(Server::new()),
     }
 }
 
-#[derive(Default)]
 pub struct Server {
     pipe_id: Option<String>,
     client_executable: Option<PathBuf>,
     show_child_window: bool,
-    child: Option<runas::Child>,
+    execution_mode: ExecutionMode,
+    elevator: Option<Box<dyn Elevator>>,
+    client_current_dir: Option<PathBuf>,
+    client_env: Vec<(String, String)>,
+    propagate_env: Vec<String>,
+    poll_interval: Duration,
+    debounce: Duration,
+    install_options: InstallOptions,
+    ipc_format: Option<ipc::Format>,
+    record_ipc: Option<PathBuf>,
+    operation_timeout: Duration,
+    in_process: InProcessConfig,
+    backend: Backend,
+    child: Option<Box<dyn ClientProcess>>,
+}
+
+/// Determines where the installation is performed
+///
+/// Ignored if a custom elevator has been set using [`Server::elevator`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExecutionMode {
+    /// Install in current process if it is already elevated, else spawn an elevated client
+    #[default]
+    Auto,
+    /// Always spawn an elevated client process using [`elevate::platform_default`]
+    Elevated,
+    /// Always install in current process using [`elevate::InProcess`], requires the process
+    /// to be already elevated, otherwise fails with [`Error::NotElevated`]
+    InProcess,
 }
 
 pub struct Client {
     pipe_name: String,
     connection_timeout: Duration,
+    limits: ipc::Limits,
+    parent_pid: Option<u32>,
+    operations: Operations,
+    policy: SecurityPolicy,
+    /// Environment variables passed by the server, see [`Self::apply_environment`]
+    environment: Vec<(String, String)>,
+    environment_applied: bool,
+    /// Running on a thread of the server process, see [`elevate::InProcess`]
+    in_process: bool,
+    backend: Backend,
+}
+
+/// State of devices and drivers as seen by the elevated client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DriverStatus {
+    /// All devices visible to the client
+    pub devices: Vec<Device>,
+    /// Driver types supported by the embedded libwdi (empty on Linux)
+    pub driver_support: Vec<DriverSupport>,
+    /// Third-party driver packages installed in the system
+    pub driver_store: Vec<DriverPackage>,
+}
+
+/// Events reported by [`Server::install_on_arrival`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WatchEvent {
+    /// Matching device appeared
+    Arrived(Device),
+    /// Matching device disappeared
+    Departed(Device),
+    /// Installation progress for the devices that arrived
+    Install(Progress),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -98,18 +445,51 @@ pub enum Progress {
     /// Installation process started (client communication established)
     Started,
     /// Installation for given device done
-    Device(Device, Result<(), String>),
+    Device(DeviceReport),
+    /// Installation attempt (counted from 1) for given device failed, next attempt starts
+    /// after `delay`
+    Retry {
+        device: Device,
+        attempt: u32,
+        error: InstallError,
+        delay: Duration,
+    },
 }
 
 impl Server {
     pub const DEFAULT_PIPE_ID: &str = "winusb-driver-installer";
 
+    /// Environment variables propagated to the client by default
+    pub const DEFAULT_PROPAGATE_ENV: &[&str] = &["RUST_LOG"];
+
+    /// Client sends heartbeats every [`rpc::HEARTBEAT_INTERVAL`]
+    const CLIENT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Timeout of the requests that do not perform any long operations
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Environment variable with path of a file to record IPC messages to, see [`Self::record_ipc`]
+    pub const RECORD_ENV_VAR: &str = "WINUSB_INSTALLER_IPC_RECORD";
+
     pub fn new() -> Self {
         Self {
             pipe_id: None,
             client_executable: None,
             child: None,
             show_child_window: false,
+            execution_mode: ExecutionMode::Auto,
+            elevator: None,
+            client_current_dir: None,
+            client_env: Vec::new(),
+            propagate_env: Self::DEFAULT_PROPAGATE_ENV.iter().map(|s| s.to_string()).collect(),
+            poll_interval: Duration::from_millis(500),
+            debounce: Duration::from_secs(1),
+            install_options: InstallOptions::default(),
+            ipc_format: None,
+            record_ipc: None,
+            operation_timeout: Duration::from_secs(60),
+            in_process: InProcessConfig::default(),
+            backend: Backend::default(),
         }
     }
 
@@ -132,20 +512,206 @@ impl Server {
         self
     }
 
+    /// Select where the installation should be performed, defaults to [`ExecutionMode::Auto`]
+    pub fn execution_mode(&mut self, mode: ExecutionMode) -> &mut Self {
+        self.execution_mode = mode;
+        self
+    }
+
+    /// Use custom strategy for launching the client instead of the one from [`Self::execution_mode`]
+    pub fn elevator(&mut self, elevator: impl Elevator + 'static) -> &mut Self {
+        self.elevator = Some(Box::new(elevator));
+        self
+    }
+
+    /// Set working directory of the spawned client process
+    ///
+    /// Passed to the elevator, so it is not supported by all of them (e.g. [`elevate::Pkexec`]).
+    /// By default it depends on the elevator.
+    pub fn client_current_dir(&mut self, dir: impl AsRef<std::path::Path>) -> &mut Self {
+        self.client_current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set environment variable in the client process
+    ///
+    /// The client only accepts variables allowed by its [`SecurityPolicy`] (by default those from
+    /// [`Client::ALLOWED_ENV_VARS`]), otherwise connecting fails with [`Error::PolicyViolation`].
+    /// Variables are passed on the command line of the client, so they should not contain
+    /// secrets.
+    pub fn client_env(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.client_env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Propagate value of given environment variable of current process to the client
+    ///
+    /// Variables from [`Self::DEFAULT_PROPAGATE_ENV`] are propagated by default. Variables that
+    /// are not set in current process are ignored. The client has to allow them, see
+    /// [`Self::client_env`].
+    pub fn propagate_env(&mut self, key: impl Into<String>) -> &mut Self {
+        self.propagate_env.push(key.into());
+        self
+    }
+
+    /// Elevated processes do not inherit environment from the server, so it is passed on the
+    /// command line instead
+    fn client_environment(&self) -> Vec<(String, String)> {
+        let propagated = self.propagate_env.iter()
+            .filter_map(|key| env::var(key).ok().map(|value| (key.clone(), value)));
+        propagated
+            .chain(self.client_env.iter().cloned())
+            .collect()
+    }
+
     /// Set path to client executable. By default [`std::env::current_exe`] is used.
     pub fn client_executable(&mut self, executable: impl AsRef<OsStr>) -> &mut Self {
         self.client_executable = Some(executable.as_ref().into());
         self
     }
 
+    /// Set how often devices are enumerated in [`Self::install_on_arrival`], defaults to 500 ms
+    pub fn poll_interval(&mut self, interval: Duration) -> &mut Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Set how long a device must stay present before [`Self::install_on_arrival`] installs
+    /// drivers for it, defaults to 1 s
+    ///
+    /// Devices that are switching to a bootloader may re-enumerate a few times.
+    pub fn debounce(&mut self, debounce: Duration) -> &mut Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Make the client verify that devices use the new driver after installation
+    ///
+    /// Devices that did not bind to the driver within the verification window are reported
+    /// as [`InstallOutcome::InstalledButNotBound`]. Disabled by default.
+    pub fn verify_installation(&mut self, verify: Option<VerifyOptions>) -> &mut Self {
+        self.install_options.verify = verify;
+        self
+    }
+
+    /// Retry installation of devices that failed with transient errors
+    ///
+    /// Defaults to [`RetryPolicy::default`], which makes a single attempt.
+    pub fn retry_policy(&mut self, policy: RetryPolicy) -> &mut Self {
+        self.install_options.retry = policy;
+        self
+    }
+
+    /// Select how to handle devices that already use the driver, defaults to
+    /// [`InstallPolicy::SkipIfPresent`]
+    pub fn install_policy(&mut self, policy: InstallPolicy) -> &mut Self {
+        self.install_options.policy = policy;
+        self
+    }
+
+    /// Install for up to `concurrency` distinct hardware IDs at the same time, defaults to 1
+    ///
+    /// On Windows libwdi is not thread-safe, so preparing and installing the driver packages
+    /// still happens one device at a time and only the rest of the work (e.g. retry delays and
+    /// reporting) overlaps. Per-device messages keep their order, but messages of different
+    /// devices may interleave in [`Progress`].
+    pub fn concurrency(&mut self, concurrency: usize) -> &mut Self {
+        self.install_options.concurrency = concurrency;
+        self
+    }
+
+    /// Set how long the client keeps installing without receiving heartbeats from the server,
+    /// defaults to 10 s
+    ///
+    /// When the server stops responding (e.g. its thread is blocked) the client cancels the
+    /// installation after the current device and exits.
+    pub fn heartbeat_tolerance(&mut self, tolerance: Duration) -> &mut Self {
+        self.install_options.heartbeat_tolerance = tolerance;
+        self
+    }
+
+    /// Select encoding of the messages exchanged with the client
+    ///
+    /// By default it is taken from [`ipc::Format::ENV_VAR`], so JSON can be enabled when
+    /// troubleshooting without rebuilding the application.
+    pub fn ipc_format(&mut self, format: ipc::Format) -> &mut Self {
+        self.ipc_format = Some(format);
+        self
+    }
+
+    /// Record all messages exchanged with the client to given file
+    ///
+    /// The file is overwritten on each connection. If not set, the path is taken from
+    /// [`Self::RECORD_ENV_VAR`]. Recordings can be replayed using [`replay`].
+    pub fn record_ipc(&mut self, path: impl AsRef<std::path::Path>) -> &mut Self {
+        self.record_ipc = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set how long [`Self::run_operation`] waits for the operation to finish, defaults to 60 s
+    pub fn operation_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.operation_timeout = timeout;
+        self
+    }
+
+    /// Set operations available to the client when it runs in the current process
+    ///
+    /// Spawned clients only know the operations registered with [`Client::register_operation`]
+    /// in the client executable. When the client runs in-process (see [`ExecutionMode`]) it is
+    /// created by the library, so the operations have to be passed here.
+    pub fn in_process_operations(&mut self, operations: Operations) -> &mut Self {
+        self.in_process.operations = operations;
+        self
+    }
+
+    /// Set the security policy of the client when it runs in the current process
+    ///
+    /// Same as [`Self::in_process_operations`], the policy of spawned clients is set by
+    /// [`Client::security_policy`] in the client executable. Defaults to
+    /// [`SecurityPolicy::default`].
+    pub fn in_process_security_policy(&mut self, policy: SecurityPolicy) -> &mut Self {
+        self.in_process.policy = policy;
+        self
+    }
+
+    /// Use given directory instead of `/` to look for sysfs when listing devices
+    ///
+    /// Only affects the devices seen by the server, a spawned client has to use the same root
+    /// (see [`Client::root`]), an in-process client uses it automatically. Mostly useful for
+    /// testing with a fake sysfs tree.
+    #[cfg(target_os = "linux")]
+    pub fn root(&mut self, root: impl AsRef<std::path::Path>) -> &mut Self {
+        self.backend.root = Some(root.as_ref().to_path_buf());
+        self
+    }
+
+    /// Check if the system has operations that will only complete after reboot
+    ///
+    /// On Windows these are pending file renames and component servicing, on Linux the
+    /// `/run/reboot-required` file. This is also checked after installation, see
+    /// [`InstallReport::reboot_required`].
+    pub fn reboot_pending(&self) -> bool {
+        backend::reboot_pending()
+    }
+
     /// List all visible devices.
     pub fn visible_devices(&self) -> io::Result<Vec<Device>> {
-        winusb::Devices::new(Box::new(|_| true))
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-            .map(|devices| devices.candidates().collect())
+        self.backend.visible_devices()
+    }
+
+    /// Take a snapshot of visible devices that can later be compared using [`DeviceSnapshot::diff`]
+    pub fn snapshot(&self) -> io::Result<DeviceSnapshot> {
+        self.visible_devices().map(DeviceSnapshot::new)
     }
 
-    fn spawn_client(&mut self) -> io::Result<runas::Child> {
+    /// List driver types supported by the embedded libwdi together with the embedded versions
+    ///
+    /// This does not require admin privileges. Always empty on Linux.
+    pub fn driver_support(&self) -> Vec<DriverSupport> {
+        backend::driver_support()
+    }
+
+    fn spawn_client(&mut self) -> io::Result<Box<dyn ClientProcess>> {
         if let Some(mut child) = self.child.take() {
             log::debug!("Killing child process");
             child.kill()?;
@@ -155,61 +721,86 @@ impl Server {
         } else {
             env::current_exe()?
         };
-        runas::Command::new(exe)
-            .arg(self.get_pipe_name())
-            .hide(!self.show_child_window)
-            .spawn()
+        let mut args: Vec<OsString> = vec![
+            self.get_pipe_name().into(),
+            format!("{}{}", Client::PARENT_PID_ARG, std::process::id()).into(),
+        ];
+        args.extend(self.client_environment().into_iter()
+            .map(|(key, value)| format!("{}{}={}", Client::ENV_ARG, key, value).into()));
+        let command = ClientCommand {
+            executable: exe,
+            args,
+            show_window: self.show_child_window,
+            current_dir: self.client_current_dir.clone(),
+            in_process: self.in_process_config(),
+        };
+        match &self.elevator {
+            Some(elevator) => elevator.spawn(&command),
+            None if self.run_in_process() => {
+                log::info!("Process already elevated, running client in current process");
+                elevate::InProcess.spawn(&command)
+            },
+            None => elevate::platform_default().spawn(&command),
+        }
     }
 
-    async fn wait_for_start(io: &mut <Installation as ipc::ProtocolTypes>::ServerChannel) -> io::Result<()> {
-        loop {
-            while let Some(msg) = io.next().await.transpose()? {
-                match msg {
-                    ClientMsg::Heatbeat => {},
-                    ClientMsg::Error(err) => log::error!("Client error: {}", err),
-                    ClientMsg::InstallStarted => return Ok(()),
-                    other => return Err(io::Error::new(io::ErrorKind::Other,
-                        format!("Unexpected message: {:?}", other))),
-                }
+    /// Add failed reports for requested devices that are missing from the report of the client
+    fn report_unhandled(report: &mut InstallReport, devices: &[Device], on_progress: &mut impl FnMut(Progress)) {
+        for dev in devices {
+            if report.devices.iter().any(|report| report.device == *dev) {
+                continue;
             }
+            log::warn!("Device {:04x}:{:04x} has not been installed by the client", dev.vid, dev.pid);
+            let unhandled = DeviceReport {
+                device: dev.clone(),
+                outcome: InstallOutcome::Failed(InstallError {
+                    code: ErrorCode::NotFound,
+                    message: "Device has not been installed by the client".to_string(),
+                }),
+                attempts: 0,
+                retried_errors: Vec::new(),
+                reboot_required: false,
+            };
+            on_progress(Progress::Device(unhandled.clone()));
+            report.devices.push(unhandled);
         }
     }
 
-    async fn wait_installation(
-        io: &mut <Installation as ipc::ProtocolTypes>::ServerChannel,
-        heartbeat_timeout: Duration,
-        mut on_progress: impl FnMut(Progress)
-    ) -> io::Result<usize> {
-        let mut last_heatbeat = Instant::now();
-        let mut installed = 0;
-        loop {
-            // Check heartbeat timeout
-            if last_heatbeat.elapsed() > heartbeat_timeout {
-                return Err(io::Error::new(io::ErrorKind::TimedOut, "No heatbeat from client"));
-            }
-            let result = match tokio::time::timeout(Duration::from_millis(100), io.next()).await {
-                Ok(result) => result,
-                Err(_) => continue, //
-            };
+    fn handle_progress(progress: Progress, reports: &mut Vec<DeviceReport>, on_progress: &mut impl FnMut(Progress)) {
+        match &progress {
+            Progress::Started => log::info!("Client started installation"),
+            Progress::Device(report) => {
+                let dev = &report.device;
+                log::info!("Installation of {:04x}:{:04x} after {} attempts: {:?}",
+                    dev.vid, dev.pid, report.attempts, report.outcome);
+                reports.push(report.clone());
+            },
+            Progress::Retry { device, attempt, error, delay } => {
+                log::warn!("Installation of {:04x}:{:04x} failed (attempt {}), retrying in {:?}: {}",
+                    device.vid, device.pid, attempt, delay, error);
+            },
+        }
+        on_progress(progress);
+    }
 
-            if let Some(msg) = result.transpose()? {
-                log::trace!("Received {:?}", msg);
-                match msg {
-                    ClientMsg::Heatbeat | ClientMsg::InstallStarted => last_heatbeat = Instant::now(),
-                    ClientMsg::InstallDone => break,
-                    ClientMsg::Error(err) => log::error!("Client error: {:?}", err),
-                    ClientMsg::DeviceInstall(dev, result) => {
-                        log::info!("Installation of {:04x}:{:04x}: {:?}", dev.vid, dev.pid, result);
-                        if result.is_ok() {
-                            installed += 1;
-                        }
-                        on_progress(Progress::Device(dev, result));
-                    },
-                }
-            }
+    /// Configuration of in-process clients, these use the same root as the server
+    fn in_process_config(&self) -> InProcessConfig {
+        InProcessConfig {
+            #[cfg(target_os = "linux")]
+            root: self.backend.root.clone(),
+            ..self.in_process.clone()
         }
+    }
 
-        Ok(installed)
+    fn run_in_process(&self) -> bool {
+        match self.execution_mode {
+            ExecutionMode::Auto => elevate::is_elevated().unwrap_or_else(|err| {
+                log::warn!("Could not check if process is elevated: {}", err);
+                false
+            }),
+            ExecutionMode::Elevated => false,
+            ExecutionMode::InProcess => true,
+        }
     }
 
     /// Perform installation for given list of devices
@@ -218,31 +809,55 @@ impl Server {
     /// the devices for installation. Note that some devices may disappear between the moment
     /// server used [`Self::visible_devices`] to find them and the moment client starts
     /// installation.
+    ///
+    /// Depending on [`Self::execution_mode`] the client runs as an elevated process or on a
+    /// separate thread of the current process.
+    ///
+    /// Devices that failed with errors considered transient by [`Self::retry_policy`] are
+    /// retried, each failed attempt is reported as [`Progress::Retry`]. Devices that already use
+    /// the driver are handled according to [`Self::install_policy`]. Devices that the client
+    /// did not install, e.g. because they disappeared or the installation got cancelled, are
+    /// reported as failed with [`ErrorCode::NotFound`]. The returned report indicates if the
+    /// system has to be rebooted for the drivers to take effect.
     pub async fn install(
         &mut self,
         config: InstallConfig,
         devices: &[Device],
         mut on_progress: impl FnMut(Progress),
-    ) -> io::Result<()> {
-        if devices.len() == 0 {
+    ) -> Result<InstallReport, Error> {
+        let config = config.canonical();
+        config.validate().map_err(Error::InvalidConfig)?;
+        if devices.is_empty() {
             log::warn!("No candidate devices found");
-            return Ok(());
+            return Ok(InstallReport::default());
         }
         log::info!("Preparing for driver installation for {} devices.", devices.len());
 
-        let pipe_name = self.get_pipe_name();
-        let server = Installation::server(&pipe_name)?;
+        let mut report = self.run_installation(config, devices, &mut on_progress).await?;
+        Self::report_unhandled(&mut report, devices, &mut on_progress);
+        report.reboot_pending = backend::reboot_pending();
+        if report.reboot_required() {
+            log::warn!("Reboot is required to complete the installation");
+        }
 
-        log::info!("Server running, spawning child.");
-        self.child = Some(self.spawn_client()?);
+        let (installed, skipped) = (report.installed(), report.skipped());
+        if report.all_installed() {
+            log::info!("Installed drivers for {}/{} devices ({} skipped).", installed, devices.len(), skipped);
+        } else {
+            log::warn!("Installed drivers for {}/{} devices ({} skipped).", installed, devices.len(), skipped);
+        }
 
-        log::info!("Waiting for client to connect");
-        let mut server = server.connect().await?;
+        Ok(report)
+    }
 
+    #[cfg(windows)]
+    async fn forward_logs(
+        caller: &rpc::Caller<Installation>,
+    ) -> Result<Option<oneshot::Sender<()>>, Error> {
         // Rely on the fact that if tx is dropped then rx receives RecvError
-        let (log_end_tx, mut log_end_rx) = oneshot::channel();
+        let (log_end_tx, mut log_end_rx) = oneshot::channel::<()>();
         if let Ok(logger) = winusb::LogReceiver::new() {
-            server.send(ServerMsg::Logging { window: logger.window() }).await?;
+            caller.call(SetupLogging(logger.window()), Self::REQUEST_TIMEOUT).response().await?;
 
             // FIXME: for some reason it doesn't work and we have rx permission error
             tokio::spawn(async move {
@@ -266,46 +881,189 @@ impl Server {
                     }
                 }
             });
+            Ok(Some(log_end_tx))
         } else {
             log::warn!("Could not initialize logging, current process may not have any windows open");
+            Ok(None)
+        }
+    }
+
+    /// Wait until a device matching `matcher` is plugged in and install drivers for it
+    ///
+    /// This is a one-shot wait: matching devices present when this is called are taken as the
+    /// baseline and ignored, unless they depart and arrive again (e.g. re-enumerate in a
+    /// bootloader). Devices are enumerated every [`Self::poll_interval`] and changes against
+    /// the previous enumeration are reported as [`WatchEvent`]s. As soon as some arrived
+    /// devices stayed present for at least [`Self::debounce`], the drivers are installed for
+    /// all of these (see [`Self::install`]) and the installation report is returned. Call this
+    /// again to wait for the next device.
+    ///
+    /// Fails with [`Error::InvalidConfig`] before watching if the configuration is not valid.
+    /// Fails with [`io::ErrorKind::TimedOut`] if no device arrives and stays present within
+    /// `timeout`.
+    pub async fn install_on_arrival(
+        &mut self,
+        config: InstallConfig,
+        matcher: impl Fn(&Device) -> bool,
+        timeout: Duration,
+        mut on_event: impl FnMut(WatchEvent),
+    ) -> Result<InstallReport, Error> {
+        let config = config.canonical();
+        config.validate().map_err(Error::InvalidConfig)?;
+        let deadline = Instant::now() + timeout;
+        let matching = |devices: Vec<Device>| -> DeviceSnapshot {
+            devices.into_iter().filter(|dev| matcher(dev)).collect()
         };
+        let mut snapshot = matching(self.poll_devices().await?);
+        log::debug!("Waiting for devices, {} matching devices already present", snapshot.len());
+        // Time when each of the arrived devices has been first seen
+        let mut first_seen = HashMap::new();
 
-        log::info!("Starting installation");
-        server.send(ServerMsg::Install(config, devices.to_vec())).await?;
+        loop {
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "No matching device arrived").into());
+            }
+            tokio::time::sleep(self.poll_interval.min(deadline - Instant::now())).await;
 
-        // Wait until client starts installation
-        tokio::time::timeout(Duration::from_secs(30), Self::wait_for_start(&mut server)).await??;
-        on_progress(Progress::Started);
+            let now = Instant::now();
+            let current = matching(self.poll_devices().await?);
+            let diff = snapshot.diff(&current);
+            for dev in diff.removed {
+                log::debug!("Device departed: {:04x}:{:04x}", dev.vid, dev.pid);
+                first_seen.remove(&dev.key());
+                on_event(WatchEvent::Departed(dev));
+            }
+            for dev in diff.added {
+                log::debug!("Device arrived: {:04x}:{:04x}", dev.vid, dev.pid);
+                first_seen.insert(dev.key(), now);
+                on_event(WatchEvent::Arrived(dev));
+            }
+            snapshot = current;
 
-        // libwdi should exit after 5 minutes
-        let install_timeout = Duration::from_secs(6 * 60);
-        // client should send heartbeat each second
-        let heartbeat_timeout = Duration::from_secs(5);
-
-        let install = Self::wait_installation(&mut server, heartbeat_timeout, on_progress);
-        let installed = match tokio::time::timeout(install_timeout, install).await {
-            Ok(installed) => installed?,
-            Err(e) => {
-                log::error!("Installation timed out");
-                server.send(ServerMsg::Exit).await.ok();
-                return Err(e.into());
-            },
-        };
+            let ready: Vec<_> = snapshot.devices()
+                .filter(|dev| first_seen.get(&dev.key())
+                    .is_some_and(|since| now.duration_since(*since) >= self.debounce))
+                .cloned()
+                .collect();
+            if !ready.is_empty() {
+                log::info!("Found {} arrived devices, installing", ready.len());
+                return self.install(config, &ready, |progress| on_event(WatchEvent::Install(progress))).await;
+            }
+        }
+    }
+
+    /// Enumerate visible devices without blocking the runtime
+    async fn poll_devices(&self) -> io::Result<Vec<Device>> {
+        let backend = self.backend.clone();
+        join_blocking(tokio::task::spawn_blocking(move || backend.visible_devices()).await)?
+    }
 
-        if server.send(ServerMsg::Exit).await.is_err() {
-            log::warn!("Could not send Exit to client");
+    /// Spawn the client and wait until it connects
+    async fn connect_client(&mut self) -> Result<rpc::Caller<Installation>, Error> {
+        let in_process = self.elevator.is_none() && self.execution_mode == ExecutionMode::InProcess;
+        if in_process && !elevate::is_elevated()? {
+            return Err(Error::NotElevated);
+        }
+        let pipe_name = self.get_pipe_name();
+        let mut server = Installation::server(&pipe_name)?;
+        if let Some(format) = self.ipc_format {
+            server.format(format);
+        }
+        let record = self.record_ipc.clone()
+            .or_else(|| env::var_os(Self::RECORD_ENV_VAR).map(PathBuf::from));
+        if let Some(path) = record {
+            log::info!("Recording IPC messages to {}", path.display());
+            server.record(ipc::Recorder::create(path)?);
         }
 
-        if installed == devices.len() {
-            log::info!("Installed drivers for {}/{} devices.", installed, devices.len());
-        } else {
-            log::warn!("Installed drivers for {}/{} devices.", installed, devices.len());
+        log::info!("Server running, spawning child.");
+        let child = self.spawn_client().map_err(Error::from_spawn)?;
+        let child = &mut **self.child.insert(child);
+
+        log::info!("Waiting for client to connect");
+        let channel = until_exit(child, server.connect()).await?;
+        let caller = rpc::Caller::spawn(channel, Self::CLIENT_HEARTBEAT_TIMEOUT);
+
+        let applied = caller.call(CheckEnvironment, Self::REQUEST_TIMEOUT).response();
+        match until_exit(child, applied).await {
+            Err(Error::Client(err)) => log::error!("Client could not apply environment: {}", err),
+            result => result??,
         }
 
-        // Just to satisfy compiler needing message type. We generally rely on Drop.
-        log_end_tx.send(()).ok();
+        Ok(caller)
+    }
 
-        Ok(())
+    fn client_process(&mut self) -> &mut dyn ClientProcess {
+        self.child.as_deref_mut().expect("Client process has not been spawned")
+    }
+
+    /// Query the state of devices and drivers as seen by the elevated client
+    ///
+    /// Some information (e.g. driver details of devices in other sessions) is only available
+    /// with admin privileges, so this spawns the client just like [`Self::install`] does.
+    pub async fn query(&mut self) -> Result<DriverStatus, Error> {
+        let caller = self.connect_client().await?;
+        let child = self.client_process();
+
+        let status = until_exit(child, caller.call(Query, Duration::from_secs(60)).response()).await;
+        caller.close().await;
+
+        status
+    }
+
+    /// Run an operation registered by the application in the client using
+    /// [`Client::register_operation`]
+    ///
+    /// This spawns the elevated client just like [`Self::install`] does. Errors returned by the
+    /// operation handler are reported as [`Error::Client`].
+    pub async fn run_operation<O: Operation>(&mut self, operation: O) -> Result<O::Output, Error> {
+        let request = RunOperation { name: O::NAME.to_string(), request: operation::encode(&operation)? };
+        let timeout = self.operation_timeout;
+        let caller = self.connect_client().await?;
+        let child = self.client_process();
+
+        log::info!("Running operation {}", O::NAME);
+        let output = until_exit(child, caller.call(request, timeout).response()).await;
+        caller.close().await;
+
+        Ok(operation::decode(O::NAME, &output?)?)
+    }
+
+    async fn run_installation(
+        &mut self,
+        config: InstallConfig,
+        devices: &[Device],
+        mut on_progress: impl FnMut(Progress),
+    ) -> Result<InstallReport, Error> {
+        let options = self.install_options.clone();
+        let caller = self.connect_client().await?;
+        let child = self.client_process();
+
+        // Log forwarding ends when the returned sender gets dropped
+        #[cfg(windows)]
+        let _log_end = Self::forward_logs(&caller).await?;
+
+        log::info!("Starting installation");
+        // libwdi should exit after 5 minutes
+        let install_timeout = Duration::from_secs(6 * 60);
+        let mut reports = Vec::new();
+        let install = caller.call(Install(config, devices.to_vec(), options), install_timeout)
+            .response_with(|progress| Self::handle_progress(progress, &mut reports, &mut on_progress));
+        let result = until_exit(child, install).await
+            .and_then(|result| result);
+        caller.close().await;
+        if let Err(err) = result {
+            log::error!("Installation failed: {}", err);
+            return Err(err);
+        }
+
+        Ok(InstallReport { devices: reports, reboot_pending: false })
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -319,13 +1077,51 @@ impl Drop for Server {
 }
 
 impl Client {
+    const PARENT_PID_ARG: &str = "--parent-pid=";
+    const ENV_ARG: &str = "--env=";
+
+    /// Environment variables that the server can set in the client (see [`Server::client_env`])
+    /// unless [`SecurityPolicy::allowed_env_vars`] is set
+    pub const ALLOWED_ENV_VARS: &[&str] = &["RUST_LOG", "RUST_LOG_STYLE", "RUST_BACKTRACE"];
+
+    /// How often the parent process is checked when [`Self::parent_pid`] is set
+    const PARENT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
     pub fn new(pipe_name: String) -> Self {
         Self {
             pipe_name,
             connection_timeout: Duration::from_secs(10),
+            limits: <Installation as Protocol>::server_msg_limits(),
+            parent_pid: None,
+            operations: Operations::default(),
+            policy: SecurityPolicy::default(),
+            environment: Vec::new(),
+            environment_applied: false,
+            in_process: false,
+            backend: Backend::default(),
         }
     }
 
+    /// Create client from the arguments passed by [`Server`] (excluding program name)
+    ///
+    /// Returns `None` if the arguments do not correspond to a client.
+    pub fn from_args(args: &[OsString]) -> Option<Self> {
+        let (pipe_name, options) = args.split_first()?;
+        let mut client = Self::new(pipe_name.to_string_lossy().into_owned());
+        for option in options {
+            let option = option.to_str()?;
+            if let Some(pid) = option.strip_prefix(Self::PARENT_PID_ARG) {
+                client.parent_pid(pid.parse().ok()?);
+            } else if let Some(var) = option.strip_prefix(Self::ENV_ARG) {
+                let (key, value) = var.split_once('=')?;
+                client.environment.push((key.to_string(), value.to_string()));
+            } else {
+                return None;
+            }
+        }
+        Some(client)
+    }
+
     pub fn pipe_name(&self) -> &str {
         &self.pipe_name
     }
@@ -335,82 +1131,520 @@ impl Client {
         self
     }
 
-    fn install_sync(io: mpsc::UnboundedSender<ClientMsg>, config: InstallConfig, devices: Vec<Device>) {
+    /// Set limits for the requests received from the server
+    ///
+    /// The client runs with admin privileges, so by default the limits are much lower than
+    /// [`ipc::Limits::default`].
+    pub fn limits(&mut self, limits: ipc::Limits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Exit when the process with given PID exits
+    ///
+    /// An ongoing installation is cancelled after the current device is finished.
+    pub fn parent_pid(&mut self, pid: u32) -> &mut Self {
+        self.parent_pid = Some(pid);
+        self
+    }
+
+    /// Register a privileged operation that can be run using [`Server::run_operation`]
+    ///
+    /// The handler runs on a blocking thread with the privileges of the client. Registering an
+    /// operation with the same [`Operation::NAME`] again replaces the handler.
+    pub fn register_operation<O, E>(
+        &mut self,
+        handler: impl Fn(O) -> Result<O::Output, E> + Send + Sync + 'static,
+    ) -> &mut Self
+    where
+        O: Operation,
+        E: std::fmt::Display,
+    {
+        self.operations.register(handler);
+        self
+    }
+
+    /// Restrict installation and environment requests that the client accepts, by default all
+    /// installations and [`Self::ALLOWED_ENV_VARS`] are allowed
+    ///
+    /// Requests violating the policy are rejected before installing anything and reported to
+    /// the server as [`Error::PolicyViolation`].
+    pub fn security_policy(&mut self, policy: SecurityPolicy) -> &mut Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Replace all registered operations
+    pub fn operations(&mut self, operations: Operations) -> &mut Self {
+        self.operations = operations;
+        self
+    }
+
+    /// Use given directory instead of `/` for sysfs and udev rules
+    ///
+    /// udev is not reloaded after installing rules under a different root. This is a setting
+    /// of the client executable, the server cannot change it.
+    #[cfg(target_os = "linux")]
+    pub fn root(&mut self, root: impl AsRef<std::path::Path>) -> &mut Self {
+        self.backend.root = Some(root.as_ref().to_path_buf());
+        self
+    }
+
+    /// Mark the client as running inside the server process
+    ///
+    /// The environment of the server is not applied, it is the same process and modifying the
+    /// environment while other threads may read it is not safe.
+    pub(crate) fn in_process(&mut self) -> &mut Self {
+        self.in_process = true;
+        self
+    }
+
+    fn is_parent_alive(&self) -> bool {
+        self.parent_pid.is_none_or(elevate::is_process_alive)
+    }
+
+    /// Resolves when the parent process exits, never if there is no parent PID
+    async fn parent_exit(&self) {
+        if self.parent_pid.is_none() {
+            return future::pending().await;
+        }
+        while self.is_parent_alive() {
+            tokio::time::sleep(Self::PARENT_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Wait until the device uses the installed driver
+    fn verify(backend: &Backend, dev: &Device, options: &VerifyOptions) -> InstallOutcome {
+        let deadline = Instant::now() + options.timeout;
+        let mut driver = None;
+        loop {
+            let key = dev.key();
+            match backend.devices(Box::new(move |d: &Device| d.key() == key)) {
+                Ok(devices) => {
+                    let found = devices.candidates().next();
+                    if found.as_ref().is_some_and(backend::is_bound) {
+                        return InstallOutcome::Installed;
+                    }
+                    driver = found.and_then(|d| d.driver);
+                },
+                Err(err) => log::warn!("Could not create device list: {}", err),
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+            std::thread::sleep(options.interval);
+        }
+        log::warn!("Device {:04x}:{:04x} did not bind to the new driver, current driver: {:?}",
+            dev.vid, dev.pid, driver);
+        InstallOutcome::InstalledButNotBound { driver }
+    }
+
+    fn install_sync(
+        backend: &Backend,
+        ctx: &rpc::Context<Installation>,
+        config: InstallConfig,
+        devices: Vec<Device>,
+        options: InstallOptions,
+    ) -> io::Result<()> {
+        if options.concurrency > 1 {
+            Self::install_concurrent(backend, ctx, &config, &options, devices)
+        } else {
+            Self::install_devices(backend, ctx, &config, &options, devices)
+        }
+    }
+
+    /// Install for the requested devices that are still present, one after another
+    ///
+    /// Stops after the current device when the request gets cancelled.
+    fn install_devices(
+        backend: &Backend,
+        ctx: &rpc::Context<Installation>,
+        config: &InstallConfig,
+        options: &InstallOptions,
+        devices: Vec<Device>,
+    ) -> io::Result<()> {
         let match_device = move |device: &Device| {
             devices.iter().any(|dev| dev == device)
         };
-        match winusb::Devices::new(Box::new(match_device)) {
-            Err(err) => {
-                log::error!("Could not create device list: {:?}", err);
-                io.send(ClientMsg::Error(err.to_string())).unwrap();
+        let devices = backend.devices(Box::new(match_device))
+            .map_err(io::Error::other)?;
+        log::info!("Found {} installation candidates", devices.candidates().count());
+
+        for (dev, result) in devices.install_iter(config, options.policy) {
+            let report = match result {
+                Some(result) => Self::install_with_retry(backend, ctx, config, options, dev, Self::install_error(result)),
+                None => DeviceReport {
+                    device: dev,
+                    outcome: InstallOutcome::Skipped,
+                    attempts: 0,
+                    retried_errors: Vec::new(),
+                    reboot_required: false,
+                },
+            };
+            ctx.send_item(Progress::Device(report));
+            if ctx.is_cancelled() {
+                log::warn!("Installation cancelled, skipping remaining devices");
+                break;
             }
-            Ok(devices) => {
-                log::info!("Found {} installation candidates", devices.candidates().count());
+        }
+        Ok(())
+    }
 
-                for (dev, result) in devices.install_iter(&config) {
-                    log::info!("Installation for device {:04x}:{:04x}: {:?}", dev.vid, dev.pid, result);
-                    let result = result.map_err(|err| err.to_string());
-                    io.send(ClientMsg::DeviceInstall(dev, result)).unwrap();
-                }
-            },
+    /// Install for groups of devices with distinct hardware IDs on up to `options.concurrency` threads
+    ///
+    /// Devices sharing a hardware ID use the same driver package, so these are installed one
+    /// after another. Each group prepares its driver package in a separate subdirectory of
+    /// [`InstallConfig::driver_path`]. Messages of a single device keep their order, but
+    /// messages of different devices may interleave.
+    fn install_concurrent(
+        backend: &Backend,
+        ctx: &rpc::Context<Installation>,
+        config: &InstallConfig,
+        options: &InstallOptions,
+        devices: Vec<Device>,
+    ) -> io::Result<()> {
+        let mut groups: Vec<(String, Vec<Device>)> = Vec::new();
+        for dev in devices {
+            let id = dev.hardware_id.clone()
+                .unwrap_or_else(|| format!("USB\\VID_{:04X}&PID_{:04X}", dev.vid, dev.pid));
+            match groups.iter_mut().find(|(group_id, _)| *group_id == id) {
+                Some((_, group)) => group.push(dev),
+                None => groups.push((id, vec![dev])),
+            }
+        }
+        let workers = options.concurrency.min(groups.len());
+        log::info!("Installing {} hardware IDs on {} threads", groups.len(), workers);
+
+        let queue = std::sync::Mutex::new(groups.into_iter());
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..workers)
+                .map(|_| scope.spawn(|| loop {
+                    let next = queue.lock().unwrap().next();
+                    let (id, group) = match next {
+                        Some(next) if !ctx.is_cancelled() => next,
+                        _ => break Ok(()),
+                    };
+                    let dir: String = id.chars()
+                        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                        .collect();
+                    let config = InstallConfig {
+                        driver_path: std::path::Path::new(&config.driver_path).join(dir).to_string_lossy().into_owned(),
+                        ..config.clone()
+                    };
+                    Self::install_devices(backend, ctx, &config, options, group)?;
+                }))
+                .collect();
+            workers.into_iter()
+                .try_for_each(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+        })
+    }
+
+    fn install_error<T>(result: backend::Result<T>) -> Result<T, InstallError> {
+        result.map_err(|err| InstallError {
+            code: backend::error_code(&err),
+            message: err.to_string(),
+        })
+    }
+
+    /// Retry installation for a device after the given result of the first attempt
+    fn install_with_retry(
+        backend: &Backend,
+        ctx: &rpc::Context<Installation>,
+        config: &InstallConfig,
+        options: &InstallOptions,
+        dev: Device,
+        mut result: Result<(), InstallError>,
+    ) -> DeviceReport {
+        let mut attempt = 1;
+        let mut retried_errors = Vec::new();
+        let outcome = loop {
+            log::info!("Installation for device {:04x}:{:04x} (attempt {}): {:?}", dev.vid, dev.pid, attempt, result);
+            let error = match result {
+                Ok(()) => break match &options.verify {
+                    Some(verify) => Self::verify(backend, &dev, verify),
+                    None => InstallOutcome::Installed,
+                },
+                Err(error) => error,
+            };
+            if !options.retry.should_retry(attempt, error.code) || ctx.is_cancelled() {
+                break InstallOutcome::Failed(error);
+            }
+            let delay = options.retry.delay(attempt);
+            ctx.send_item(Progress::Retry { device: dev.clone(), attempt, error: error.clone(), delay });
+            retried_errors.push(error);
+            std::thread::sleep(delay);
+            attempt += 1;
+            result = Self::reinstall(backend, &dev, config);
         };
+        // Another installation that could not finish is most likely waiting for a reboot
+        let reboot_required = match &outcome {
+            InstallOutcome::Failed(error) => error.code == ErrorCode::PendingInstallation,
+            _ => backend::reboot_required(&dev),
+        };
+        if reboot_required {
+            log::warn!("Device {:04x}:{:04x} requires reboot", dev.vid, dev.pid);
+        }
+        DeviceReport {
+            device: dev,
+            outcome,
+            attempts: attempt,
+            retried_errors,
+            reboot_required,
+        }
+    }
+
+    /// Enumerate devices again (the device may have re-enumerated) and install for the device
+    fn reinstall(backend: &Backend, dev: &Device, config: &InstallConfig) -> Result<(), InstallError> {
+        let key = dev.key();
+        let devices = Self::install_error(backend.devices(Box::new(move |d: &Device| d.key() == key)))?;
+        // Installation has already been decided, so don't skip
+        let result = devices.install_iter(config, InstallPolicy::Always).next().and_then(|(_, result)| result);
+        match result {
+            Some(result) => Self::install_error(result),
+            None => Err(InstallError {
+                code: ErrorCode::NoDevice,
+                message: "Device not found".to_string(),
+            }),
+        }
     }
 
     async fn install(
-        &mut self,
-        io: &mut <Installation as ProtocolTypes>::ClientChannel,
+        backend: Backend,
+        ctx: rpc::Context<Installation>,
         config: InstallConfig,
         devices: Vec<Device>,
+        options: InstallOptions,
     ) -> io::Result<()> {
-        // Create a separate thread for installation because it uses blocking calls to libwdi
-        // This thread will send messages to current task which will send these and heartbeats to server.
-        let (tx, mut rx) = mpsc::unbounded_channel();
-        let installer = tokio::task::spawn_blocking(move || {
-            log::trace!("Started blocking installation thread");
-            Self::install_sync(tx, config, devices);
-        });
-
-        log::trace!("Started heatbeat");
-        loop {
-            io.send(ClientMsg::Heatbeat).await.unwrap();
-            match tokio::time::timeout(Duration::from_millis(1000), rx.recv()).await {
-                Ok(Some(msg)) => io.send(msg).await?,
-                Ok(None) => break, // Channel closed which means that thread finished
-                Err(_) => {}, // loop timed out, just send next heartbeat
-            }
-        }
+        ctx.send_item(Progress::Started);
+        // When the server stops sending heartbeats (e.g. its thread is blocked) the connection
+        // ends, which cancels the installation after the current device
+        ctx.keepalive_timeout(Some(options.heartbeat_tolerance));
+
+        // Create a separate thread for installation because it uses blocking calls to libwdi,
+        // progress is streamed from that thread directly
+        let installer = {
+            let ctx = ctx.clone();
+            tokio::task::spawn_blocking(move || {
+                log::trace!("Started blocking installation thread");
+                Self::install_sync(&backend, &ctx, config, devices, options)
+            })
+        };
+        let result = join_blocking(installer.await);
 
-        installer.await?;
+        ctx.keepalive_timeout(None);
+        result?
+    }
 
+    fn query_status(backend: &Backend) -> io::Result<DriverStatus> {
+        let devices = backend.devices(Box::new(|_| true))
+            .map_err(io::Error::other)?
+            .candidates()
+            .collect();
+        Ok(DriverStatus {
+            devices,
+            driver_support: backend::driver_support(),
+            driver_store: backend.driver_store()?,
+        })
+    }
+
+    /// Apply the environment variables passed by the server
+    ///
+    /// The server is not trusted, so nothing is applied if any of the variables is not allowed
+    /// by the [`SecurityPolicy`]. Should be called after setting the policy and before
+    /// initializing logging, so that e.g. `RUST_LOG` takes effect. Otherwise it is called by
+    /// [`Self::serve`]. Rejected variables are reported to the server as
+    /// [`Error::PolicyViolation`].
+    pub fn apply_environment(&mut self) -> Result<(), PolicyViolation> {
+        self.check_environment()?;
+        if self.environment_applied {
+            return Ok(());
+        }
+        self.environment_applied = true;
+        if self.in_process {
+            log::debug!("Running in the server process, not applying environment");
+            return Ok(());
+        }
+        for (key, value) in &self.environment {
+            log::debug!("Setting {}", key);
+            env::set_var(key, value);
+        }
         Ok(())
     }
 
+    fn check_environment(&self) -> Result<(), PolicyViolation> {
+        self.environment.iter().try_for_each(|(key, _)| self.policy.check_env_var(key))
+    }
+
     /// Serve the installation (this is client in the sense of IPC, but a server in terms of
     /// installation process).
+    ///
+    /// Panics and errors that end serving are reported to the server as [`Error::ClientFatal`]
+    /// before returning. Panics are converted to errors. Location and backtrace of panics are
+    /// only reported when the client has been created by [`init`].
     pub async fn serve(&mut self) -> io::Result<()> {
-        let mut client = Installation::client(&self.pipe_name, self.connection_timeout).await?;
+        if let Err(violation) = self.apply_environment() {
+            log::error!("Rejecting environment: {}", violation);
+        }
+        let mut client = Installation::client_with_limits(&self.pipe_name, self.connection_timeout, self.limits).await?;
 
-        loop {
-            if let Some(msg) = client.try_next().await? {
-                log::trace!("Received {:?}", msg);
-
-                match msg {
-                    ServerMsg::Exit => break,
-                    ServerMsg::Logging { window } => winusb::LogReceiver::client_setup(window)?,
-                    ServerMsg::Install(config, devices) => {
-                        log::debug!("Got driver installation request");
-                        client.send(ClientMsg::InstallStarted).await?;
-                        self.install(&mut client, config, devices).await?;
-                        client.send(ClientMsg::InstallDone).await?;
+        let fatal = match AssertUnwindSafe(self.handle_requests(&mut client)).catch_unwind().await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(err)) => {
+                let fatal = ClientFatal { message: err.to_string(), location: None, backtrace: None };
+                client.send(rpc::ResponderMsg::Abort(fatal)).await.ok();
+                return Err(err);
+            },
+            Err(panic) => take_last_panic().unwrap_or_else(|| ClientFatal {
+                message: panic_message(&*panic),
+                location: None,
+                backtrace: None,
+            }),
+        };
+        log::error!("Client panicked: {}", fatal);
+        client.send(rpc::ResponderMsg::Abort(fatal.clone())).await.ok();
+        Err(io::Error::other(fatal.to_string()))
+    }
+
+    async fn handle_requests<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        client: &mut rpc::ResponderChannel<Installation, IO>,
+    ) -> io::Result<()> {
+        let parent_exit = async {
+            self.parent_exit().await;
+            log::error!("Parent process exited");
+            io::Error::new(io::ErrorKind::BrokenPipe, "Parent process exited")
+        };
+        rpc::serve(client, |request, ctx| self.handle_request(request, ctx), parent_exit).await
+    }
+
+    async fn handle_request(&self, request: Request, ctx: rpc::Context<Installation>) -> Result<Response, String> {
+        let result = match request {
+            Request::Environment => match self.check_environment() {
+                Ok(()) => Ok(Response::Done),
+                Err(violation) => Ok(Response::Rejected(violation)),
+            },
+            #[cfg(windows)]
+            Request::Logging { window } => {
+                winusb::LogReceiver::client_setup(window).map(|()| Response::Done)
+            },
+            Request::Query => {
+                log::debug!("Got status query");
+                let backend = self.backend.clone();
+                join_blocking(tokio::task::spawn_blocking(move || Self::query_status(&backend)).await)
+                    .and_then(|status| status)
+                    .map(Response::Status)
+            },
+            Request::Install(config, devices, options) => {
+                log::debug!("Got driver installation request");
+                let config = config.canonical();
+                if let Err(err) = config.validate() {
+                    log::error!("Rejecting installation request: {}", err);
+                    return Ok(Response::InvalidConfig(err));
+                }
+                match self.policy.check(&config, &devices, backend::DRIVER_TYPE) {
+                    Ok(()) => {
+                        Self::install(self.backend.clone(), ctx, config, devices, options).await
+                            .map(|()| Response::Done)
+                    },
+                    Err(violation) => {
+                        log::error!("Rejecting installation request: {}", violation);
+                        Ok(Response::Rejected(violation))
                     },
                 }
+            },
+            Request::Operation { name, request } => {
+                log::debug!("Got operation {}", name);
+                let operations = self.operations.clone();
+                let run = move || operations.run(&name, &request)
+                    .unwrap_or_else(|| Err(format!("Unknown operation {}", name)));
+                join_blocking(tokio::task::spawn_blocking(run).await)
+                    .and_then(|output| output.map_err(io::Error::other))
+                    .map(Response::Operation)
+            },
+        };
+        result.map_err(|err| {
+            log::error!("Request failed: {}", err);
+            err.to_string()
+        })
+    }
+}
+
+/// Run the future until completion unless the child process exits earlier
+///
+/// Client exits only after the connection is closed (see [`rpc::Caller::close`]), so exiting
+/// earlier means that it either crashed or was never started properly (e.g. user declined the
+/// UAC prompt).
+async fn until_exit<T, E: Into<Error>>(
+    child: &mut dyn ClientProcess,
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, Error> {
+    futures::pin_mut!(fut);
+    tokio::select! {
+        result = &mut fut => result.map_err(Into::into),
+        exited = child.wait_async() => {
+            exited?;
+            // Messages sent right before exiting (e.g. the abort message) may still be buffered
+            let drained = tokio::time::timeout(Duration::from_millis(500), fut).await
+                .map(|result| result.map_err(Into::into));
+            match drained {
+                Ok(Ok(value)) => Ok(value),
+                Ok(Err(err @ Error::ClientFatal(_))) => Err(err),
+                _ => {
+                    log::error!("Client process exited unexpectedly");
+                    Err(Error::ClientExited)
+                },
             }
-        }
+        },
+    }
+}
 
-        Ok(())
+/// Most recent panic captured by the hook from [`install_panic_hook`]
+static LAST_PANIC: Mutex<Option<ClientFatal>> = Mutex::new(None);
+
+/// Capture location and backtrace of panics, these are not available in `catch_unwind`
+///
+/// The hook applies to the whole process, so it is only installed in the client process and
+/// not when the client runs inside the server (see [`elevate::InProcess`]). The previous hook
+/// is still called, so panics are printed as usual.
+fn install_panic_hook() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let fatal = ClientFatal {
+                message: panic_message(info.payload()),
+                location: info.location().map(|location| location.to_string()),
+                backtrace: Some(Backtrace::force_capture().to_string()),
+            };
+            *LAST_PANIC.lock().unwrap_or_else(|e| e.into_inner()) = Some(fatal);
+            previous(info);
+        }));
+    });
+}
+
+fn take_last_panic() -> Option<ClientFatal> {
+    LAST_PANIC.lock().unwrap_or_else(|e| e.into_inner()).take()
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload.downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "Unknown panic payload".to_string()),
+    }
+}
+
+/// Propagate panics from blocking tasks so that [`Client::serve`] can report them
+fn join_blocking<T>(result: Result<T, tokio::task::JoinError>) -> io::Result<T> {
+    match result {
+        Ok(value) => Ok(value),
+        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+        Err(err) => Err(err.into()),
     }
 }
 
+#[cfg(windows)]
 async fn sleep_ms(ms: u64) {
     tokio::time::sleep(Duration::from_millis(ms)).await;
 }
@@ -1,11 +1,14 @@
+use std::env;
 use std::io::Write;
 
-use winusb_installer::{Mode, InstallConfig};
+use winusb_installer::{Mode, InstallConfig, Error, Server};
 
 fn init_logging(name: &str) {
     let name = name.to_string();
-    env_logger::builder()
+    // RUST_LOG overrides the default level
+    env_logger::Builder::new()
         .filter_level(log::LevelFilter::Trace)
+        .parse_default_env()
         .format_timestamp(None)
         .format(move |buf, record| {
             // writeln!(buf, "[{} {} {}] {}",
@@ -16,8 +19,27 @@ fn init_logging(name: &str) {
         .init();
 }
 
+fn print_driver_support(server: &Server) {
+    for support in server.driver_support() {
+        if !support.supported {
+            println!("{:?}: not supported", support.driver);
+            continue;
+        }
+        let version = support.version.map_or("unknown".to_string(), |v| v.to_string());
+        match support.date {
+            Some(date) => println!("{:?}: version {}, date {} (Unix time)", support.driver, version, date),
+            None => println!("{:?}: version {}", support.driver, version),
+        }
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
+    if env::args().nth(1).as_deref() == Some("--driver-support") {
+        print_driver_support(&Server::new());
+        return;
+    }
+
     let mode = winusb_installer::init();
     match mode {
         Mode::Server(mut server) => {
@@ -34,23 +56,34 @@ async fn main() {
 
             log::debug!("Devices = {devices:#?}");
 
-            let config = InstallConfig {
-                vendor: "my-vendor".to_string(),
-                driver_path: "C:\\usb_driver".to_string(),
-                inf_name: "MyWinUSB.inf".to_string(),
-            };
+            let config = InstallConfig::new("my-vendor", "MyWinUSB.inf");
 
             if !devices.is_empty() {
                 log::info!("Driver installation needed, installing.");
-                server.install(config, &devices, |_| {}).await.unwrap();
+                match server.install(config, &devices, |_| {}).await {
+                    Ok(report) if report.reboot_required() => log::warn!("Reboot required to finish the installation."),
+                    Ok(report) if !report.all_installed() => log::warn!("Some installations failed: {:#?}", report),
+                    Ok(_) => {},
+                    Err(Error::ElevationDeclined) => log::warn!("Installation cancelled by the user."),
+                    Err(err) => panic!("Installation failed: {}", err),
+                }
             } else {
                 log::info!("Driver installation not needed.");
             }
         },
         Mode::Client(mut client) => {
+            // Before logging is initialized, so that RUST_LOG passed by the server takes effect
+            let environment = client.apply_environment();
             init_logging("child");
+            if let Err(violation) = environment {
+                log::error!("Environment rejected: {}", violation);
+            }
             log::info!("Starting with: {}", client.pipe_name());
-            client.serve().await.unwrap();
+            // Failures have already been reported to the server
+            if let Err(err) = client.serve().await {
+                log::error!("Client failed: {}", err);
+                std::process::exit(1);
+            }
         },
     }
 }
@@ -0,0 +1,80 @@
+//! Privileged operations defined by the application
+//!
+//! Operations are registered in the client using [`crate::Client::register_operation`] and run
+//! from the server using [`crate::Server::run_operation`]. Requests and outputs are transmitted
+//! as JSON, independently of the IPC message format.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+/// Request of an operation performed by the elevated client
+///
+/// The same executable acts as the server and the client, so both see the same type.
+pub trait Operation: Serialize + DeserializeOwned {
+    /// Name identifying the operation, must be unique within the application
+    const NAME: &'static str;
+
+    /// Result of the operation returned to the server
+    type Output: Serialize + DeserializeOwned;
+}
+
+/// Type-erased handler, taking the request and returning the output as JSON
+type Handler = Arc<dyn Fn(&str) -> Result<String, String> + Send + Sync>;
+
+/// Handlers of registered operations
+///
+/// Usually operations are registered directly with [`crate::Client::register_operation`], this
+/// is only needed to pass the same operations to an in-process client, see
+/// [`crate::Server::in_process_operations`].
+#[derive(Clone, Default)]
+pub struct Operations(Arc<HashMap<String, Handler>>);
+
+impl Operations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register handler of an operation, replacing any handler with the same [`Operation::NAME`]
+    pub fn register<O, E>(&mut self, handler: impl Fn(O) -> Result<O::Output, E> + Send + Sync + 'static) -> &mut Self
+    where
+        O: Operation,
+        E: fmt::Display,
+    {
+        let handler: Handler = Arc::new(move |request| {
+            let request = serde_json::from_str(request)
+                .map_err(|e| format!("Invalid {} request: {}", O::NAME, e))?;
+            let output = handler(request).map_err(|e| e.to_string())?;
+            serde_json::to_string(&output).map_err(|e| e.to_string())
+        });
+        Arc::make_mut(&mut self.0).insert(O::NAME.to_string(), handler);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run the operation with given name, `None` if it is not registered
+    pub(crate) fn run(&self, name: &str, request: &str) -> Option<Result<String, String>> {
+        self.0.get(name).map(|handler| handler(request))
+    }
+}
+
+impl fmt::Debug for Operations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+pub(crate) fn encode<T: Serialize>(value: &T) -> io::Result<String> {
+    Ok(serde_json::to_string(value)?)
+}
+
+pub(crate) fn decode<T: DeserializeOwned>(name: &str, data: &str) -> io::Result<T> {
+    serde_json::from_str(data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {} output: {}", name, e)))
+}
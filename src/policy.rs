@@ -0,0 +1,267 @@
+//! Restrictions on what the elevated client installs, see [`crate::Client::security_policy`]
+
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{Client, Device, DriverType, InstallConfig};
+
+/// Policy enforced by the client before installing anything
+///
+/// The client runs with admin privileges, so it should not trust the requests blindly. The
+/// policy is set in the client executable (or loaded from a configuration file only writable
+/// by administrators). The default policy allows everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecurityPolicy {
+    /// Devices that drivers can be installed for, any device if `None`
+    pub allowed_devices: Option<Vec<DeviceMatcher>>,
+    /// Driver types that can be installed, any if `None`
+    pub allowed_driver_types: Option<Vec<DriverType>>,
+    /// Directories in which driver packages can be created (see [`InstallConfig::driver_path`]),
+    /// any if `None`
+    pub allowed_driver_roots: Option<Vec<PathBuf>>,
+    /// Maximum number of devices in a single installation request
+    pub max_devices: Option<usize>,
+    /// Environment variables that the server can set (see [`crate::Server::client_env`]),
+    /// [`Client::ALLOWED_ENV_VARS`] if `None`
+    pub allowed_env_vars: Option<Vec<String>>,
+}
+
+/// Matches devices by vendor ID and optionally product ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceMatcher {
+    pub vid: u16,
+    /// Any product of the vendor if `None`
+    pub pid: Option<u16>,
+}
+
+/// Reason why the client rejected an installation request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PolicyViolation {
+    /// Device does not match any of [`SecurityPolicy::allowed_devices`]
+    DeviceNotAllowed { vid: u16, pid: u16 },
+    /// Driver type is not one of [`SecurityPolicy::allowed_driver_types`]
+    DriverTypeNotAllowed(DriverType),
+    /// Driver path is not inside any of [`SecurityPolicy::allowed_driver_roots`]
+    DriverPathNotAllowed(String),
+    /// Request contains more devices than [`SecurityPolicy::max_devices`]
+    TooManyDevices { requested: usize, max: usize },
+    /// Environment variable is not one of [`SecurityPolicy::allowed_env_vars`]
+    EnvVarNotAllowed(String),
+}
+
+impl DeviceMatcher {
+    /// Match all products of given vendor
+    pub fn vid(vid: u16) -> Self {
+        Self { vid, pid: None }
+    }
+
+    pub fn vid_pid(vid: u16, pid: u16) -> Self {
+        Self { vid, pid: Some(pid) }
+    }
+
+    pub fn matches(&self, dev: &Device) -> bool {
+        self.vid == dev.vid && self.pid.is_none_or(|pid| pid == dev.pid)
+    }
+}
+
+impl SecurityPolicy {
+    /// Check installation of `driver` for the devices using given configuration
+    pub fn check(&self, config: &InstallConfig, devices: &[Device], driver: DriverType) -> Result<(), PolicyViolation> {
+        if let Some(max) = self.max_devices {
+            if devices.len() > max {
+                return Err(PolicyViolation::TooManyDevices { requested: devices.len(), max });
+            }
+        }
+        if let Some(allowed) = &self.allowed_driver_types {
+            if !allowed.contains(&driver) {
+                return Err(PolicyViolation::DriverTypeNotAllowed(driver));
+            }
+        }
+        if let Some(roots) = &self.allowed_driver_roots {
+            if !roots.iter().any(|root| is_inside(Path::new(&config.driver_path), root, cfg!(windows))) {
+                return Err(PolicyViolation::DriverPathNotAllowed(config.driver_path.clone()));
+            }
+        }
+        if let Some(allowed) = &self.allowed_devices {
+            if let Some(dev) = devices.iter().find(|dev| !allowed.iter().any(|matcher| matcher.matches(dev))) {
+                return Err(PolicyViolation::DeviceNotAllowed { vid: dev.vid, pid: dev.pid });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the server can set the environment variable with given name in the client
+    pub fn check_env_var(&self, name: &str) -> Result<(), PolicyViolation> {
+        let allowed = match &self.allowed_env_vars {
+            Some(allowed) => allowed.iter().any(|var| var == name),
+            None => Client::ALLOWED_ENV_VARS.contains(&name),
+        };
+        if !allowed {
+            return Err(PolicyViolation::EnvVarNotAllowed(name.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Check if the path is the root or inside it, paths with `..` are never inside
+///
+/// Windows paths are case-insensitive, so `ignore_case` should be set there.
+fn is_inside(path: &Path, root: &Path, ignore_case: bool) -> bool {
+    if path.components().any(|component| component == Component::ParentDir) {
+        return false;
+    }
+    if ignore_case {
+        let path = PathBuf::from(path.to_string_lossy().to_lowercase());
+        let root = PathBuf::from(root.to_string_lossy().to_lowercase());
+        path.starts_with(root)
+    } else {
+        path.starts_with(root)
+    }
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeviceNotAllowed { vid, pid } => write!(f, "Device {:04x}:{:04x} is not allowed", vid, pid),
+            Self::DriverTypeNotAllowed(driver) => write!(f, "Driver type {:?} is not allowed", driver),
+            Self::DriverPathNotAllowed(path) => write!(f, "Driver path {} is not allowed", path),
+            Self::TooManyDevices { requested, max } => {
+                write!(f, "Too many devices requested ({}, at most {} allowed)", requested, max)
+            },
+            Self::EnvVarNotAllowed(name) => write!(f, "Environment variable {} is not allowed", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(vid: u16, pid: u16) -> Device {
+        Device {
+            vid,
+            pid,
+            is_composite: false,
+            mi: None,
+            driver_version: None,
+            desc: String::new(),
+            driver: None,
+            device_id: None,
+            hardware_id: None,
+            compatible_id: None,
+            upper_filter: None,
+        }
+    }
+
+    fn config(driver_path: &str) -> InstallConfig {
+        InstallConfig {
+            vendor: "Vendor".to_string(),
+            driver_path: driver_path.to_string(),
+            inf_name: "a.inf".to_string(),
+        }
+    }
+
+    #[test]
+    fn default_allows_everything() {
+        let devices: Vec<_> = (0..100).map(|pid| device(0x1234, pid)).collect();
+        assert_eq!(SecurityPolicy::default().check(&config("/anywhere"), &devices, DriverType::User), Ok(()));
+    }
+
+    #[test]
+    fn device_not_allowed() {
+        let policy = SecurityPolicy {
+            allowed_devices: Some(vec![DeviceMatcher::vid(0x1209), DeviceMatcher::vid_pid(0x1234, 0x0001)]),
+            ..SecurityPolicy::default()
+        };
+        let config = config("/opt/drv");
+        let check = |devices: &[Device]| policy.check(&config, devices, DriverType::WinUsb);
+        assert_eq!(check(&[device(0x1209, 0x0001), device(0x1209, 0xffff), device(0x1234, 0x0001)]), Ok(()));
+        assert_eq!(check(&[device(0x1209, 0x0001), device(0x1234, 0x0002)]),
+            Err(PolicyViolation::DeviceNotAllowed { vid: 0x1234, pid: 0x0002 }));
+        assert_eq!(check(&[device(0x1208, 0x0001)]), Err(PolicyViolation::DeviceNotAllowed { vid: 0x1208, pid: 0x0001 }));
+        assert_eq!(check(&[]), Ok(()));
+    }
+
+    #[test]
+    fn driver_type_not_allowed() {
+        let policy = SecurityPolicy {
+            allowed_driver_types: Some(vec![DriverType::WinUsb, DriverType::LibUsbK]),
+            ..SecurityPolicy::default()
+        };
+        let config = config("/opt/drv");
+        let devices = [device(0x1209, 0x0001)];
+        assert_eq!(policy.check(&config, &devices, DriverType::WinUsb), Ok(()));
+        assert_eq!(policy.check(&config, &devices, DriverType::LibUsbK), Ok(()));
+        assert_eq!(policy.check(&config, &devices, DriverType::User),
+            Err(PolicyViolation::DriverTypeNotAllowed(DriverType::User)));
+    }
+
+    #[test]
+    fn driver_path_not_allowed() {
+        let policy = SecurityPolicy {
+            allowed_driver_roots: Some(vec![PathBuf::from("/opt/drv"), PathBuf::from("/var/lib/winusb-installer")]),
+            ..SecurityPolicy::default()
+        };
+        let devices = [device(0x1209, 0x0001)];
+        for path in ["/opt/drv", "/opt/drv/vendor", "/var/lib/winusb-installer/vendor"] {
+            assert_eq!(policy.check(&config(path), &devices, DriverType::WinUsb), Ok(()), "{:?}", path);
+        }
+        for path in ["/opt/drv2", "/opt/drv/../etc", "/opt", "/etc/drv", "opt/drv"] {
+            assert_eq!(policy.check(&config(path), &devices, DriverType::WinUsb),
+                Err(PolicyViolation::DriverPathNotAllowed(path.to_string())), "{:?}", path);
+        }
+        let nothing = SecurityPolicy { allowed_driver_roots: Some(Vec::new()), ..SecurityPolicy::default() };
+        assert!(nothing.check(&config("/opt/drv"), &devices, DriverType::WinUsb).is_err());
+    }
+
+    #[test]
+    fn too_many_devices() {
+        let policy = SecurityPolicy { max_devices: Some(2), ..SecurityPolicy::default() };
+        let config = config("/opt/drv");
+        let devices: Vec<_> = (0..3).map(|pid| device(0x1209, pid)).collect();
+        assert_eq!(policy.check(&config, &devices[..2], DriverType::WinUsb), Ok(()));
+        assert_eq!(policy.check(&config, &devices, DriverType::WinUsb),
+            Err(PolicyViolation::TooManyDevices { requested: 3, max: 2 }));
+    }
+
+    #[test]
+    fn env_var_not_allowed() {
+        let default = SecurityPolicy::default();
+        assert_eq!(default.check_env_var("RUST_LOG"), Ok(()));
+        assert_eq!(default.check_env_var("PATH"), Err(PolicyViolation::EnvVarNotAllowed("PATH".to_string())));
+        assert_eq!(default.check_env_var("rust_log"), Err(PolicyViolation::EnvVarNotAllowed("rust_log".to_string())));
+
+        let policy = SecurityPolicy { allowed_env_vars: Some(vec!["MY_VAR".to_string()]), ..SecurityPolicy::default() };
+        assert_eq!(policy.check_env_var("MY_VAR"), Ok(()));
+        assert_eq!(policy.check_env_var("RUST_LOG"), Err(PolicyViolation::EnvVarNotAllowed("RUST_LOG".to_string())));
+        let nothing = SecurityPolicy { allowed_env_vars: Some(Vec::new()), ..SecurityPolicy::default() };
+        assert!(nothing.check_env_var("RUST_LOG").is_err());
+    }
+
+    #[test]
+    fn inside() {
+        let inside = |path: &str, root: &str| is_inside(Path::new(path), Path::new(root), false);
+        assert!(inside("/opt/drv", "/opt/drv"));
+        assert!(inside("/opt/drv/", "/opt/drv"));
+        assert!(inside("/opt/drv/vendor", "/opt/drv/"));
+        assert!(inside("/opt//drv/./vendor", "/opt/drv"));
+        assert!(!inside("/opt/drv2", "/opt/drv"));
+        assert!(!inside("/opt/drv2/vendor", "/opt/drv"));
+        assert!(!inside("/opt", "/opt/drv"));
+        assert!(!inside("/opt/drv/../etc", "/opt/drv"));
+        assert!(!inside("/opt/drv/vendor/..", "/opt/drv"));
+        assert!(!inside("/opt/drv/..", "/opt"));
+        assert!(!inside("/OPT/DRV/vendor", "/opt/drv"));
+    }
+
+    #[test]
+    fn inside_ignore_case() {
+        let inside = |path: &str, root: &str| is_inside(Path::new(path), Path::new(root), true);
+        assert!(inside("C:/Program Files/Vendor", "c:/program files"));
+        assert!(inside("c:/PROGRAM FILES/vendor", "C:/Program Files"));
+        assert!(!inside("C:/Program Files Evil/x", "C:/Program Files"));
+        assert!(!inside("C:/Program Files/../Users", "c:/program files"));
+    }
+}
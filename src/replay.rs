@@ -0,0 +1,143 @@
+//! Replaying recorded IPC traffic
+//!
+//! Recordings are made by the server, see [`crate::Server::record_ipc`]. Replaying feeds one
+//! side of the recorded conversation to the real implementation of the other side over an
+//! in-memory stream, so protocol-level problems can be reproduced without spawning the
+//! elevated client.
+
+use std::io;
+use std::time::Duration;
+
+use futures::prelude::*;
+use serde::de::DeserializeOwned;
+
+use crate::ipc::{self, rpc, Direction, Format, Limits, Record};
+use crate::{Client, ClientMsg, DeviceReport, Error, Installation, Progress, Server, ServerMsg};
+
+/// Feed recorded client messages to the server side of the protocol
+///
+/// The recorded requests are made again in the same order, so that they get the same IDs as the
+/// recorded responses. With `realtime` the recorded delays between messages are kept, else the
+/// messages are sent as fast as possible. Returns the device reports collected by the server.
+pub async fn replay_server(
+    records: &[Record],
+    realtime: bool,
+    mut on_progress: impl FnMut(Progress),
+) -> Result<Vec<DeviceReport>, Error> {
+    let requests = messages::<ServerMsg>(records, Direction::ServerToClient)?;
+    let messages = messages::<ClientMsg>(records, Direction::ClientToServer)?;
+    let (server, client) = ipc::duplex::<ClientMsg, ServerMsg>(Format::default(), Limits::default());
+    let caller = rpc::Caller::<Installation>::spawn(server, Server::CLIENT_HEARTBEAT_TIMEOUT);
+
+    // Replayed responses arrive no later than the recorded ones
+    let timeout = records.last().map_or(Duration::ZERO, |record| record.elapsed) + Duration::from_secs(60);
+    let calls: Vec<_> = requests.into_iter()
+        .filter_map(|(_, msg)| match msg {
+            rpc::CallerMsg::Request { body, .. } => Some(body),
+            _ => None,
+        })
+        .map(|request| caller.call(request, timeout))
+        .collect();
+
+    let peer = async move {
+        let (mut tx, mut rx) = client.split();
+        // Heartbeats of the server must be read so that the stream does not fill up
+        let drain = async {
+            while let Some(msg) = rx.next().await {
+                log::trace!("Server sent {:?}", msg);
+            }
+        };
+        let (fed, ()) = future::join(feed(&mut tx, messages, realtime), drain).await;
+        fed
+    };
+
+    let mut reports = Vec::new();
+    let responses = async {
+        for call in calls {
+            let id = call.id();
+            let response = call.response_with(|progress| Server::handle_progress(progress, &mut reports, &mut on_progress)).await?;
+            log::debug!("Response to request {}: {:?}", id, response);
+        }
+        Ok::<_, Error>(())
+    };
+
+    tokio::select! {
+        result = responses => result?,
+        fed = peer => {
+            fed?;
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Replayed client disconnected").into());
+        },
+    }
+    Ok(reports)
+}
+
+/// Feed recorded server requests to the client's request handling
+///
+/// Requests are executed for real, so replaying an installation request installs drivers (udev
+/// rules on Linux). Requests that would modify the current process (logging on Windows) are
+/// skipped and the connection is closed after the last message if it was not recorded. Without
+/// `realtime` the recorded heartbeats are sent at once, so long installations may get cancelled
+/// by [`crate::InstallOptions::heartbeat_tolerance`]. Returns the messages sent by the client,
+/// with times relative to the start of the replay.
+///
+/// The requests are handled by given `client`, on Linux [`Client::root`] can be used to install
+/// the rules under a different directory.
+pub async fn replay_client(client: &Client, records: &[Record], realtime: bool) -> io::Result<Vec<Record>> {
+    let mut requests = messages::<ServerMsg>(records, Direction::ServerToClient)?;
+    #[cfg(windows)]
+    requests.retain(|(_, msg)| !matches!(msg, rpc::CallerMsg::Request { body: crate::Request::Logging { .. }, .. }));
+    if !requests.iter().any(|(_, msg)| matches!(msg, rpc::CallerMsg::Close)) {
+        let last = requests.last().map_or(Duration::ZERO, |(elapsed, _)| *elapsed);
+        requests.push((last, rpc::CallerMsg::Close));
+    }
+
+    let (mut client_io, server) = ipc::duplex::<ServerMsg, ClientMsg>(Format::default(), Limits::default());
+    let serve = async move {
+        let result = client.handle_requests(&mut client_io).await;
+        // Close the stream so that collecting the responses ends
+        drop(client_io);
+        result
+    };
+
+    let start = tokio::time::Instant::now();
+    let peer = async move {
+        let (mut tx, rx) = server.split();
+        let collect = rx
+            .map(|msg| {
+                let message = serde_json::to_value(msg?)?;
+                Ok(Record { elapsed: start.elapsed(), direction: Direction::ClientToServer, message })
+            })
+            .try_collect::<Vec<_>>();
+        let (fed, responses) = future::join(feed(&mut tx, requests, realtime), collect).await;
+        fed?;
+        responses
+    };
+
+    let (served, responses) = future::join(serve, peer).await;
+    served?;
+    responses
+}
+
+/// Decode recorded messages sent in given direction together with their times
+fn messages<T: DeserializeOwned>(records: &[Record], direction: Direction) -> io::Result<Vec<(Duration, T)>> {
+    records.iter()
+        .filter(|record| record.direction == direction)
+        .map(|record| Ok((record.elapsed, record.decode()?)))
+        .collect()
+}
+
+async fn feed<T>(
+    sink: &mut (impl Sink<T, Error = io::Error> + Unpin),
+    messages: Vec<(Duration, T)>,
+    realtime: bool,
+) -> io::Result<()> {
+    let start = tokio::time::Instant::now();
+    let offset = messages.first().map_or(Duration::ZERO, |(elapsed, _)| *elapsed);
+    for (elapsed, msg) in messages {
+        if realtime {
+            tokio::time::sleep_until(start + elapsed.saturating_sub(offset)).await;
+        }
+        sink.send(msg).await?;
+    }
+    Ok(())
+}
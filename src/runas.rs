@@ -1,14 +1,17 @@
 //! Spawn a process with elevated privileges on Windows using "runas"
 
-use std::ffi::OsString;
+use std::ffi::{c_void, OsString};
 use std::io;
 use std::ffi::OsStr;
 use std::path::Path;
 use std::time::Duration;
 
+use tokio::sync::oneshot;
 use windows::core::*;
 use windows::Win32::System::Threading::TerminateProcess;
 use windows::Win32::Foundation;
+use windows::Win32::Security;
+use windows::Win32::System::SystemServices;
 use windows::Win32::System::Threading;
 use windows::Win32::System::WindowsProgramming::INFINITE;
 use windows::Win32::UI::Shell;
@@ -23,6 +26,29 @@ pub struct Command {
     cwd: Option<HSTRING>,
 }
 
+/// Reason of a failure to spawn the process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// User declined the elevation request (clicked "No" in the UAC prompt)
+    Cancelled,
+    /// Executable file not found
+    FileNotFound,
+    /// Path to the executable not found
+    PathNotFound,
+    /// Access to the executable denied
+    AccessDenied,
+    /// Not enough memory to spawn the process
+    OutOfMemory,
+    /// No application associated with the executable file
+    NoAssociation,
+    /// ShellExecuteExW reported success but no process handle has been returned
+    NoProcess,
+    /// Other `SE_ERR_*` code returned in `hInstApp`
+    Shell(u32),
+    /// Other error code returned by `GetLastError`
+    Os(u32),
+}
+
 /// Handle to a running process with admin privileges
 pub struct Child {
     // Storing the HSTRINGs that were used as PCWSTR to avoid dropping the inner memory
@@ -129,22 +155,13 @@ impl Command {
         // With SEE_MASK_NOCLOSEPROCESS hInstApp is set to >=32 on success or SE_ERR_XXX on failure
         unsafe {
             if !Shell::ShellExecuteExW(&mut exec_info).as_bool() {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "ShellExecuteExW failed"
-                ));
+                return Err(SpawnError::from_last_error(Foundation::GetLastError()).into());
             }
         }
         if let err @ 0..=31 = exec_info.hInstApp.0 as u32 {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                se_err_string(err),
-            ));
+            return Err(SpawnError::from_se_err(err).into());
         } else if exec_info.hProcess.is_invalid() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "No process was spawned",
-            ));
+            return Err(SpawnError::NoProcess.into());
         }
 
         Ok(Child {
@@ -184,6 +201,20 @@ impl Child {
             .map(|res| assert!(res.is_some()))
     }
 
+    /// Asynchronously wait for process completion.
+    ///
+    /// Unlike [`Self::wait`] this does not block the thread. The process handle is waited on by
+    /// the system thread pool, which wakes the task when the process exits.
+    pub async fn wait_async(&self) -> io::Result<()> {
+        if self.try_wait_raw(0)?.is_some() {
+            return Ok(());
+        }
+        let (tx, rx) = oneshot::channel();
+        // Unregistered when dropped, also when the future gets dropped before the process exits
+        let _wait = ProcessWait::register(self.process_handle, tx)?;
+        rx.await.map_err(|_| io::Error::other("Process wait ended unexpectedly"))
+    }
+
     /// Kill a running process, will succeed if the process already exited.
     pub fn kill(&mut self) -> io::Result<()> {
         // Don't kill if it already exited
@@ -209,6 +240,172 @@ impl Drop for Child {
     }
 }
 
+/// Wait for a process handle registered with `RegisterWaitForSingleObject`
+struct ProcessWait {
+    wait_handle: Foundation::HANDLE,
+    /// Sender notified by [`process_exited`], owned by this struct
+    context: *mut Option<oneshot::Sender<()>>,
+}
+
+impl ProcessWait {
+    fn register(process_handle: Foundation::HANDLE, exited: oneshot::Sender<()>) -> io::Result<Self> {
+        let context = Box::into_raw(Box::new(Some(exited)));
+        let mut wait_handle = Foundation::HANDLE::default();
+        let ok = unsafe {
+            Threading::RegisterWaitForSingleObject(
+                &mut wait_handle,
+                process_handle,
+                Some(process_exited),
+                Some(context as *const c_void),
+                INFINITE,
+                Threading::WT_EXECUTEONLYONCE,
+            )
+        };
+        if !ok.as_bool() {
+            let err = io::Error::last_os_error();
+            drop(unsafe { Box::from_raw(context) });
+            return Err(err);
+        }
+        Ok(Self { wait_handle, context })
+    }
+}
+
+// The context is only accessed by the callback and, after unregistering, by drop
+unsafe impl Send for ProcessWait {}
+
+impl Drop for ProcessWait {
+    fn drop(&mut self) {
+        unsafe {
+            // With INVALID_HANDLE_VALUE this waits for a running callback, so the context can be freed
+            Threading::UnregisterWaitEx(self.wait_handle, Foundation::INVALID_HANDLE_VALUE);
+            drop(Box::from_raw(self.context));
+        }
+    }
+}
+
+/// Called on a thread pool thread when the process handle gets signalled
+unsafe extern "system" fn process_exited(context: *mut c_void, _timed_out: Foundation::BOOLEAN) {
+    let exited = &mut *(context as *mut Option<oneshot::Sender<()>>);
+    if let Some(exited) = exited.take() {
+        exited.send(()).ok();
+    }
+}
+
+impl SpawnError {
+    fn from_last_error(err: Foundation::WIN32_ERROR) -> Self {
+        match err {
+            Foundation::ERROR_CANCELLED => Self::Cancelled,
+            Foundation::ERROR_FILE_NOT_FOUND => Self::FileNotFound,
+            Foundation::ERROR_PATH_NOT_FOUND => Self::PathNotFound,
+            Foundation::ERROR_ACCESS_DENIED => Self::AccessDenied,
+            Foundation::ERROR_NOT_ENOUGH_MEMORY => Self::OutOfMemory,
+            Foundation::ERROR_NO_ASSOCIATION => Self::NoAssociation,
+            other => Self::Os(other.0),
+        }
+    }
+
+    fn from_se_err(err: u32) -> Self {
+        match err {
+            Shell::SE_ERR_FNF => Self::FileNotFound,
+            Shell::SE_ERR_PNF => Self::PathNotFound,
+            Shell::SE_ERR_ACCESSDENIED => Self::AccessDenied,
+            Shell::SE_ERR_OOM => Self::OutOfMemory,
+            Shell::SE_ERR_NOASSOC => Self::NoAssociation,
+            other => Self::Shell(other),
+        }
+    }
+
+    /// Retrieve the spawn error from an error returned by [`Command::spawn`]
+    pub fn from_io(err: &io::Error) -> Option<Self> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<Self>())
+            .copied()
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::Cancelled | Self::AccessDenied => io::ErrorKind::PermissionDenied,
+            Self::FileNotFound | Self::PathNotFound => io::ErrorKind::NotFound,
+            Self::OutOfMemory => io::ErrorKind::OutOfMemory,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cancelled => write!(f, "The operation was cancelled by the user."),
+            Self::FileNotFound => write!(f, "File not found."),
+            Self::PathNotFound => write!(f, "Path not found."),
+            Self::AccessDenied => write!(f, "Access denied."),
+            Self::OutOfMemory => write!(f, "Out of memory."),
+            Self::NoAssociation => write!(f, "File association not available."),
+            Self::NoProcess => write!(f, "No process was spawned."),
+            Self::Shell(err) => write!(f, "{}", se_err_string(*err)),
+            Self::Os(err) => write!(f, "ShellExecuteExW failed with error code {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
+impl From<SpawnError> for io::Error {
+    fn from(err: SpawnError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// Check if the current process is running with administrator privileges
+///
+/// Checks if the token of the current process is a member of the Administrators group,
+/// with UAC enabled this is true only for elevated processes.
+pub fn is_elevated() -> io::Result<bool> {
+    let mut sid = Foundation::PSID::default();
+    let ok = unsafe {
+        Security::AllocateAndInitializeSid(
+            &Security::SECURITY_NT_AUTHORITY,
+            2,
+            SystemServices::SECURITY_BUILTIN_DOMAIN_RID as u32,
+            SystemServices::DOMAIN_ALIAS_RID_ADMINS as u32,
+            0, 0, 0, 0, 0, 0,
+            &mut sid,
+        )
+    };
+    if !ok.as_bool() {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut is_member = Foundation::BOOL(0);
+    let result = unsafe {
+        // Null token handle means that the token of the calling thread/process is used
+        if Security::CheckTokenMembership(Foundation::HANDLE(0), sid, &mut is_member).as_bool() {
+            Ok(is_member.as_bool())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    };
+    unsafe {
+        Security::FreeSid(sid);
+    }
+    result
+}
+
+/// Check if the process with given PID is still running
+///
+/// Returns `false` if the process cannot be opened, which also happens when it exited.
+pub fn is_process_alive(pid: u32) -> bool {
+    let handle = match unsafe { Threading::OpenProcess(Threading::PROCESS_SYNCHRONIZE, false, pid) } {
+        Ok(handle) => handle,
+        Err(_) => return false,
+    };
+    unsafe {
+        let status = Threading::WaitForSingleObject(handle, 0);
+        Foundation::CloseHandle(handle);
+        status == Foundation::WAIT_TIMEOUT
+    }
+}
+
 fn se_err_string(err: u32) -> String {
     match err {
         Shell::SE_ERR_FNF => "File not found.".into(),
@@ -0,0 +1,531 @@
+//! Granting user access to USB devices on Linux by installing udev rules
+//!
+//! This is the Linux counterpart of [`crate::winusb`]. Devices are enumerated from sysfs and
+//! "installation" adds a rule for the device's VID/PID to a rules file in `/etc/udev/rules.d`
+//! and reloads udev. All paths are resolved relative to a root directory, which allows to
+//! generate the rules in a temporary directory.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+pub use crate::device::{Device, DeviceFilter, DriverPackage, DriverSupport, DriverType, InstallConfig};
+pub use crate::install::{ErrorCode, InstallPolicy};
+
+pub type Result<T> = io::Result<T>;
+
+/// Driver type that corresponds to the installed rules, which grant userspace access to the devices
+pub const DRIVER_TYPE: DriverType = DriverType::WinUsb;
+
+/// Serializes modifications of rules files, which are read-modify-write
+static RULES_LOCK: Mutex<()> = Mutex::new(());
+
+/// List of detected USB devices for udev rules installation
+pub struct Devices {
+    list: Vec<Device>,
+    filter: Box<DeviceFilter>,
+    root: PathBuf,
+}
+
+impl Devices {
+    pub fn new(filter: Box<DeviceFilter>) -> io::Result<Self> {
+        Self::with_root("/", filter)
+    }
+
+    /// Use given directory instead of `/` to look for sysfs and udev rules
+    ///
+    /// udev is only reloaded after installation when the root is `/`.
+    pub fn with_root(root: impl AsRef<Path>, filter: Box<DeviceFilter>) -> io::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        let list = enumerate(&root)?;
+        Ok(Self {
+            list,
+            filter,
+            root,
+        })
+    }
+
+    fn candidates_ref(&self) -> impl Iterator<Item = &Device> {
+        self.list.iter()
+            .filter(|dev| (self.filter)(dev))
+    }
+
+    pub fn candidates(&self) -> impl Iterator<Item = Device> + '_ {
+        self.candidates_ref()
+            .cloned()
+            .inspect(|dev| log::trace!("Candidate device: {:#?}", dev))
+    }
+
+    pub fn is_install_needed(&self) -> bool {
+        self.candidates_ref().count() > 0
+    }
+
+    /// Install rules for all candidates, the result is `None` for devices skipped due to `policy`
+    ///
+    /// With [`InstallPolicy::Always`] udev is reloaded even if the rule was already present.
+    pub fn install_iter<'a>(
+        &'a self,
+        config: &'a InstallConfig,
+        policy: InstallPolicy,
+    ) -> impl Iterator<Item = (Device, Option<io::Result<()>>)> + 'a {
+        let rules = Rules::new(&self.root, config);
+        let force = policy == InstallPolicy::Always;
+        self.candidates_ref()
+            .map(move |dev| {
+                if !force && rules.contains(dev).unwrap_or(false) {
+                    log::info!("Skipping {:04x}:{:04x}, rule already present", dev.vid, dev.pid);
+                    return (dev.clone(), None);
+                }
+                log::debug!("Installing for: {:#?}", dev);
+                (dev.clone(), Some(self.install(&rules, dev, force)))
+            })
+    }
+
+    fn install(&self, rules: &Rules, dev: &Device, force: bool) -> io::Result<()> {
+        let _lock = RULES_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        if (rules.add(dev)? || force) && self.root == Path::new("/") {
+            reload(dev)?;
+        }
+        Ok(())
+    }
+}
+
+/// udev rules file generated for given [`InstallConfig`]
+pub struct Rules {
+    path: PathBuf,
+    vendor: String,
+}
+
+impl Rules {
+    /// Directory with udev rules relative to the root directory
+    pub const RULES_DIR: &str = "etc/udev/rules.d";
+
+    pub fn new(root: &Path, config: &InstallConfig) -> Self {
+        let stem = Path::new(&config.inf_name)
+            .file_stem()
+            .map_or_else(|| config.inf_name.clone(), |stem| stem.to_string_lossy().into_owned());
+        // uaccess tag must be applied before 73-seat-late.rules
+        let path = root.join(Self::RULES_DIR).join(format!("70-{}.rules", stem));
+        Self {
+            path,
+            vendor: config.vendor.clone(),
+        }
+    }
+
+    /// Path to the rules file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rule granting access to given device for the user logged in on the local seat
+    pub fn rule(dev: &Device) -> String {
+        format!(
+            r#"SUBSYSTEM=="usb", ATTRS{{idVendor}}=="{:04x}", ATTRS{{idProduct}}=="{:04x}", TAG+="uaccess""#,
+            dev.vid, dev.pid,
+        )
+    }
+
+    const HEADER_PREFIX: &str = "# USB device access rules for ";
+    const HEADER_SUFFIX: &str = ", generated by winusb-installer";
+
+    fn header(&self) -> String {
+        format!("{}{}{}\n", Self::HEADER_PREFIX, self.vendor, Self::HEADER_SUFFIX)
+    }
+
+    /// Vendor name if the content has been generated by [`Rules`]
+    fn parse_header(content: &str) -> Option<&str> {
+        content.lines().next()?
+            .strip_prefix(Self::HEADER_PREFIX)?
+            .strip_suffix(Self::HEADER_SUFFIX)
+    }
+
+    /// Check if the rules file contains rule for the device
+    pub fn contains(&self, dev: &Device) -> io::Result<bool> {
+        let rule = Self::rule(dev);
+        match fs::read_to_string(&self.path) {
+            Ok(content) => Ok(content.lines().any(|line| line == rule)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Add rule for the device to the rules file (creating it if needed)
+    ///
+    /// Returns `false` if the file already contained the rule.
+    pub fn add(&self, dev: &Device) -> io::Result<bool> {
+        let rule = Self::rule(dev);
+        let mut content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.header(),
+            Err(e) => return Err(e),
+        };
+        if content.lines().any(|line| line == rule) {
+            log::debug!("Rule for {:04x}:{:04x} already present in {}", dev.vid, dev.pid, self.path.display());
+            return Ok(false);
+        }
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(&rule);
+        content.push('\n');
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first so that udev never sees partially written rules
+        let tmp = self.path.with_extension("rules.tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)?;
+        log::info!("Added rule for {:04x}:{:04x} to {}", dev.vid, dev.pid, self.path.display());
+        Ok(true)
+    }
+}
+
+/// Map I/O error to the platform-independent error code used by [`crate::RetryPolicy`]
+pub fn error_code(err: &io::Error) -> ErrorCode {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => ErrorCode::Access,
+        io::ErrorKind::NotFound => ErrorCode::NotFound,
+        io::ErrorKind::AlreadyExists => ErrorCode::Exists,
+        io::ErrorKind::WouldBlock => ErrorCode::Busy,
+        io::ErrorKind::TimedOut => ErrorCode::Timeout,
+        io::ErrorKind::Interrupted => ErrorCode::Interrupted,
+        io::ErrorKind::Unsupported => ErrorCode::NotSupported,
+        io::ErrorKind::OutOfMemory => ErrorCode::Resource,
+        _ => ErrorCode::Io,
+    }
+}
+
+/// udev rules do not change the driver used by the device, so it is always considered bound
+pub fn is_bound(_dev: &Device) -> bool {
+    true
+}
+
+/// udev rules apply immediately after reloading
+pub fn reboot_required(_dev: &Device) -> bool {
+    false
+}
+
+/// Check for `/run/reboot-required` created by package managers (e.g. on Debian/Ubuntu)
+pub fn reboot_pending() -> bool {
+    Path::new("/run/reboot-required").exists()
+}
+
+/// There are no driver types to choose from on Linux
+pub fn driver_support() -> Vec<DriverSupport> {
+    Vec::new()
+}
+
+/// List udev rules files generated by this crate
+pub fn driver_store() -> io::Result<Vec<DriverPackage>> {
+    driver_store_in(Path::new("/"))
+}
+
+/// Same as [`driver_store`] but relative to given root directory
+pub fn driver_store_in(root: &Path) -> io::Result<Vec<DriverPackage>> {
+    let dir = root.join(Rules::RULES_DIR);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut packages = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let content = match fs::read_to_string(entry.path()) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        if let Some(vendor) = Rules::parse_header(&content) {
+            packages.push(DriverPackage {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                provider: Some(vendor.to_string()),
+                class: None,
+                version: None,
+            });
+        }
+    }
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(packages)
+}
+
+/// Locations of udevadm, an absolute path is used so that `PATH` does not matter
+const UDEVADM_PATHS: &[&str] = &["/usr/bin/udevadm", "/bin/udevadm", "/usr/sbin/udevadm", "/sbin/udevadm"];
+
+fn udevadm(args: &[&str]) -> io::Result<()> {
+    let udevadm = UDEVADM_PATHS.iter()
+        .find(|path| Path::new(path).is_file())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "udevadm not found"))?;
+    let status = process::Command::new(udevadm).args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("udevadm {} failed: {}", args.join(" "), status)))
+    }
+}
+
+/// Reload udev rules and re-trigger events for the device so that the new rules get applied
+fn reload(dev: &Device) -> io::Result<()> {
+    udevadm(&["control", "--reload-rules"])?;
+    let vid = format!("idVendor={:04x}", dev.vid);
+    let pid = format!("idProduct={:04x}", dev.pid);
+    udevadm(&["trigger", "--action=add", "--subsystem-match=usb", "--attr-match", &vid, "--attr-match", &pid])
+}
+
+const USB_CLASS_HUB: u16 = 0x09;
+
+/// List USB devices (but not interfaces or hubs) from sysfs
+fn enumerate(root: &Path) -> io::Result<Vec<Device>> {
+    let dir = root.join("sys/bus/usb/devices");
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Interfaces are named like "1-2:1.0", root hubs like "usb1"
+        if name.contains(':') || name.starts_with("usb") {
+            continue;
+        }
+        match read_device(&entry.path(), &name) {
+            Ok(Some(dev)) => devices.push(dev),
+            Ok(None) => {},
+            Err(err) => log::warn!("Could not read device {}: {}", name, err),
+        }
+    }
+    devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+    Ok(devices)
+}
+
+fn read_attr(path: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(path.join(attr))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+fn read_hex(path: &Path, attr: &str) -> io::Result<Option<u16>> {
+    match read_attr(path, attr) {
+        Some(s) => u16::from_str_radix(&s, 16)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", attr, e))),
+        None => Ok(None),
+    }
+}
+
+fn read_device(path: &Path, name: &str) -> io::Result<Option<Device>> {
+    let (vid, pid) = match (read_hex(path, "idVendor")?, read_hex(path, "idProduct")?) {
+        (Some(vid), Some(pid)) => (vid, pid),
+        _ => return Ok(None),
+    };
+    // Hubs are handled by the kernel hub driver
+    if read_hex(path, "bDeviceClass")? == Some(USB_CLASS_HUB) {
+        return Ok(None);
+    }
+    let interfaces: usize = read_attr(path, "bNumInterfaces")
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1);
+    let desc = read_attr(path, "product")
+        .unwrap_or_else(|| format!("USB device {:04x}:{:04x}", vid, pid));
+    Ok(Some(Device {
+        vid,
+        pid,
+        is_composite: interfaces > 1,
+        mi: None,
+        driver_version: None,
+        desc,
+        driver: interface_driver(path, name),
+        device_id: Some(name.to_string()),
+        hardware_id: Some(format!("usb:v{:04X}p{:04X}", vid, pid)),
+        compatible_id: None,
+        upper_filter: None,
+    }))
+}
+
+/// Name of the driver bound to the first interface that has one
+fn interface_driver(path: &Path, name: &str) -> Option<String> {
+    let prefix = format!("{}:", name);
+    let mut interfaces: Vec<_> = fs::read_dir(path).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        .map(|entry| entry.path())
+        .collect();
+    interfaces.sort();
+    interfaces.iter()
+        .filter_map(|interface| fs::read_link(interface.join("driver")).ok())
+        .find_map(|driver| driver.file_name().map(|name| name.to_string_lossy().into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Temporary root directory with a fake sysfs tree, removed when dropped
+    struct TempRoot(PathBuf);
+
+    impl TempRoot {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("winusb-installer-udev-{}-{}", name, std::process::id()));
+            if path.exists() {
+                fs::remove_dir_all(&path).unwrap();
+            }
+            fs::create_dir_all(path.join("sys/bus/usb/devices")).unwrap();
+            Self(path)
+        }
+
+        fn add_device(&self, name: &str, vid: u16, pid: u16, interfaces: usize) {
+            let devices = self.0.join("sys/bus/usb/devices");
+            let dir = devices.join(name);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("idVendor"), format!("{:04x}\n", vid)).unwrap();
+            fs::write(dir.join("idProduct"), format!("{:04x}\n", pid)).unwrap();
+            fs::write(dir.join("bNumInterfaces"), format!("{:2}\n", interfaces)).unwrap();
+            for i in 0..interfaces {
+                fs::create_dir_all(devices.join(format!("{}:1.{}", name, i))).unwrap();
+            }
+        }
+
+        fn devices(&self) -> Devices {
+            Devices::with_root(&self.0, Box::new(|_| true)).unwrap()
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn config() -> InstallConfig {
+        InstallConfig::new("Test Vendor", "test.inf")
+    }
+
+    fn device(vid: u16, pid: u16) -> Device {
+        Device {
+            vid,
+            pid,
+            is_composite: false,
+            mi: None,
+            driver_version: None,
+            desc: String::new(),
+            driver: None,
+            device_id: None,
+            hardware_id: None,
+            compatible_id: None,
+            upper_filter: None,
+        }
+    }
+
+    #[test]
+    fn enumerate_devices() {
+        let root = TempRoot::new("enumerate");
+        root.add_device("1-2", 0x1209, 0x0001, 1);
+        root.add_device("1-1", 0xcafe, 0x4001, 2);
+        // Not a device
+        fs::create_dir_all(root.0.join("sys/bus/usb/devices/usb1")).unwrap();
+        // Root hub and external hub with all attributes
+        root.add_device("usb2", 0x1d6b, 0x0002, 1);
+        root.add_device("1-4", 0x05e3, 0x0608, 1);
+        for hub in ["usb2", "1-4"] {
+            fs::write(root.0.join("sys/bus/usb/devices").join(hub).join("bDeviceClass"), "09\n").unwrap();
+        }
+        // Root hub of a controller without the device class
+        root.add_device("usb3", 0x1d6b, 0x0003, 1);
+
+        let devices: Vec<_> = root.devices().candidates().collect();
+        assert_eq!(devices.len(), 2);
+        assert_eq!((devices[0].vid, devices[0].pid), (0xcafe, 0x4001));
+        assert!(devices[0].is_composite);
+        assert_eq!(devices[0].desc, "USB device cafe:4001");
+        assert_eq!(devices[1].device_id.as_deref(), Some("1-2"));
+        assert_eq!(devices[1].hardware_id.as_deref(), Some("usb:v1209p0001"));
+        assert!(!devices[1].is_composite);
+    }
+
+    #[test]
+    fn rule_text() {
+        assert_eq!(
+            Rules::rule(&device(0x1209, 0xab)),
+            r#"SUBSYSTEM=="usb", ATTRS{idVendor}=="1209", ATTRS{idProduct}=="00ab", TAG+="uaccess""#,
+        );
+        let rules = Rules::new(Path::new("/root"), &config());
+        assert_eq!(rules.path(), Path::new("/root/etc/udev/rules.d/70-test.rules"));
+    }
+
+    #[test]
+    fn header_and_driver_store() {
+        let root = TempRoot::new("header");
+        let rules = Rules::new(&root.0, &config());
+        assert!(rules.add(&device(0x1209, 0x0001)).unwrap());
+
+        let content = fs::read_to_string(rules.path()).unwrap();
+        assert_eq!(content.lines().next(), Some("# USB device access rules for Test Vendor, generated by winusb-installer"));
+        assert_eq!(Rules::parse_header(&content), Some("Test Vendor"));
+        assert_eq!(Rules::parse_header("# Some other rules\n"), None);
+
+        // Rules not generated by this crate are not listed
+        fs::write(root.0.join(Rules::RULES_DIR).join("99-other.rules"), "# Other\n").unwrap();
+        let store = driver_store_in(&root.0).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store[0].name, "70-test.rules");
+        assert_eq!(store[0].provider.as_deref(), Some("Test Vendor"));
+    }
+
+    #[test]
+    fn no_duplicate_rules() {
+        let root = TempRoot::new("duplicates");
+        let rules = Rules::new(&root.0, &config());
+        let (first, second) = (device(0x1209, 0x0001), device(0x1209, 0x0002));
+        assert!(!rules.contains(&first).unwrap());
+        assert!(rules.add(&first).unwrap());
+        assert!(!rules.add(&first).unwrap());
+        assert!(rules.add(&second).unwrap());
+        assert!(rules.contains(&first).unwrap() && rules.contains(&second).unwrap());
+
+        let content = fs::read_to_string(rules.path()).unwrap();
+        assert_eq!(content.lines().filter(|line| *line == Rules::rule(&first)).count(), 1);
+        assert_eq!(content.lines().count(), 3);
+    }
+
+    #[test]
+    fn atomic_write() {
+        let root = TempRoot::new("atomic");
+        let rules = Rules::new(&root.0, &config());
+        rules.add(&device(0x1209, 0x0001)).unwrap();
+        let old = fs::read_to_string(rules.path()).unwrap();
+        // A hard link keeps the old file if the rules are replaced instead of modified in place
+        let link = root.0.join("old.rules");
+        fs::hard_link(rules.path(), &link).unwrap();
+        // Leftover of an interrupted write
+        let tmp = rules.path().with_extension("rules.tmp");
+        fs::write(&tmp, "garbage").unwrap();
+
+        rules.add(&device(0x1209, 0x0002)).unwrap();
+        assert_eq!(fs::read_to_string(&link).unwrap(), old);
+        assert!(fs::read_to_string(rules.path()).unwrap().starts_with(&old));
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn install_policies() {
+        let root = TempRoot::new("policies");
+        root.add_device("1-2", 0x1209, 0x0001, 1);
+        let devices = root.devices();
+        let config = config();
+
+        let results: Vec<_> = devices.install_iter(&config, InstallPolicy::SkipIfPresent).collect();
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0].1, Some(Ok(()))));
+
+        // Rule already present
+        let results: Vec<_> = devices.install_iter(&config, InstallPolicy::SkipIfPresent).collect();
+        assert!(results[0].1.is_none());
+        let results: Vec<_> = devices.install_iter(&config, InstallPolicy::ReinstallIfOlder).collect();
+        assert!(results[0].1.is_none());
+
+        // Installed again, without duplicating the rule
+        let results: Vec<_> = devices.install_iter(&config, InstallPolicy::Always).collect();
+        assert!(matches!(results[0].1, Some(Ok(()))));
+        let content = fs::read_to_string(Rules::new(&root.0, &config).path()).unwrap();
+        assert_eq!(content.lines().count(), 2);
+    }
+}
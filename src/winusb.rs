@@ -1,15 +1,36 @@
+//! WinUSB driver installation on Windows using libwdi
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::c_void;
+use std::fs;
 use std::io;
-use std::num::{NonZeroU64, NonZeroU8};
+use std::path::PathBuf;
+use std::sync::Mutex;
 
 use libwdi as wdi;
 use serde::{Serialize, Deserialize};
-use windows::Win32::Foundation::{LPARAM, HWND, BOOL};
+use windows::core::{w, GUID, HSTRING, PCWSTR};
+use windows::Win32::Devices::DeviceAndDriverInstallation as Cfg;
+use windows::Win32::Foundation::{LPARAM, HANDLE, HWND, BOOL, ERROR_SUCCESS};
+use windows::Win32::System::Com;
+use windows::Win32::System::Registry;
 use windows::Win32::System::Threading;
+use windows::Win32::UI::Shell;
 use windows::Win32::UI::WindowsAndMessaging;
 
+pub use crate::device::{Device, DeviceFilter, DriverPackage, DriverSupport, DriverType, DriverVersion, InstallConfig};
+pub use crate::install::{ErrorCode, InstallPolicy};
+
 pub type Result<T> = wdi::Result<T>;
 
-pub type DeviceFilter = dyn Fn(&Device) -> bool + Send;
+/// Driver type installed for the devices
+pub const DRIVER_TYPE: DriverType = DriverType::WinUsb;
+
+/// libwdi uses global state in device enumeration, driver preparation and installation, and
+/// refuses to install while another installation is pending, so all of these calls are
+/// serialized
+static LIBWDI_LOCK: Mutex<()> = Mutex::new(());
 
 /// List of detected USB devices for driver installation
 pub struct Devices {
@@ -17,38 +38,15 @@ pub struct Devices {
     filter: Box<DeviceFilter>,
 }
 
-/// Device information. Owned version of [`libwdi::DeviceInfo`].
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct Device {
-    pub vid: u16,
-    pub pid: u16,
-    pub is_composite: bool,
-    pub mi: Option<NonZeroU8>,
-    pub driver_version: Option<NonZeroU64>,
-    pub desc: String,
-    pub driver: Option<String>,
-    pub device_id: Option<String>,
-    pub hardware_id: Option<String>,
-    pub compatible_id: Option<String>,
-    pub upper_filter: Option<String>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct InstallConfig {
-    /// Name that will be visible as the "Manufacturer" device property in device manager
-    pub vendor: String,
-    /// The directory where the .inf and driver files should be crated, e.g. `C:\usb_driver`
-    pub driver_path: String,
-    /// The name of the .inf file to generate (includeing the .inf extension)
-    pub inf_name: String,
-}
-
 impl Devices {
     pub fn new(filter: Box<DeviceFilter>) -> wdi::Result<Self> {
         setup_logs();
-        let list = wdi::CreateListOptions::new()
-            .list_all(true)
-            .create_list()?;
+        let list = {
+            let _lock = LIBWDI_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            wdi::CreateListOptions::new()
+                .list_all(true)
+                .create_list()?
+        };
         Ok(Self {
             list,
             filter,
@@ -78,10 +76,25 @@ impl Devices {
     //     Ok(())
     // }
 
-    pub fn install_iter<'a>(&'a self, config: &'a InstallConfig) -> impl Iterator<Item = (Device, wdi::Result<()>)> + '_ {
+    /// Install driver for all candidates, the result is `None` for devices skipped due to `policy`
+    pub fn install_iter<'a>(
+        &'a self,
+        config: &'a InstallConfig,
+        policy: InstallPolicy,
+    ) -> impl Iterator<Item = (Device, Option<wdi::Result<()>>)> + 'a {
+        let embedded = embedded_version(DriverType::WinUsb);
         self.candidates_ref()
-            .inspect(|dev| log::debug!("Installing for: {:#?}", Device::from(dev)))
-            .map(|dev| (Device::from(&dev), install_winusb(dev, config)))
+            .map(move |dev| {
+                let device = Device::from(&dev);
+                if is_install_needed(&device, policy, embedded) {
+                    log::debug!("Installing for: {:#?}", device);
+                    (device, Some(install_winusb(dev, config)))
+                } else {
+                    log::info!("Skipping {:04x}:{:04x}, driver already installed: {:?}",
+                        device.vid, device.pid, device.driver_version.map(|v| DriverVersion::from(v.get())));
+                    (device, None)
+                }
+            })
     }
 
     // /// Install for all while processing results. Return `false` from `f` to stop immediatelly.
@@ -140,13 +153,6 @@ impl LogReceiver {
     }
 }
 
-impl Device {
-    /// Convenience method for checking if device has WinUSB driver installed
-    pub fn has_winusb(&self) -> bool {
-        self.driver.as_ref().map_or(false, |driver| driver.to_lowercase() == "winusb")
-    }
-}
-
 impl<'a> From<&wdi::DeviceInfo<'a>> for Device {
     fn from(dev: &wdi::DeviceInfo<'a>) -> Self {
         Self {
@@ -165,11 +171,119 @@ impl<'a> From<&wdi::DeviceInfo<'a>> for Device {
     }
 }
 
+fn is_install_needed(dev: &Device, policy: InstallPolicy, embedded: Option<DriverVersion>) -> bool {
+    match policy {
+        InstallPolicy::Always => true,
+        InstallPolicy::SkipIfPresent => !dev.has_winusb(),
+        InstallPolicy::ReinstallIfOlder => match (dev.has_winusb(), dev.driver_version, embedded) {
+            (false, _, _) => true,
+            (true, Some(installed), Some(embedded)) => DriverVersion::from(installed.get()) < embedded,
+            // Unknown versions, keep the driver
+            (true, _, _) => false,
+        },
+    }
+}
+
+/// Version of the driver embedded in libwdi
+fn embedded_version(driver: DriverType) -> Option<DriverVersion> {
+    wdi::is_driver_supported(driver.into())
+        .map(|info| DriverVersion::from_ms_ls(info.0.dwFileVersionMS, info.0.dwFileVersionLS))
+}
+
+/// Check if the device uses the driver installed by [`Devices::install_iter`]
+///
+/// The device must use [`DRIVER_TYPE`]. If both the installed version and the version embedded
+/// in libwdi are known, the installed one must be at least the embedded one, so that a device
+/// still bound to an older driver is not reported as bound.
+pub fn is_bound(dev: &Device) -> bool {
+    if !dev.has_winusb() {
+        return false;
+    }
+    match (dev.driver_version, embedded_version(DRIVER_TYPE)) {
+        (Some(installed), Some(embedded)) => DriverVersion::from(installed.get()) >= embedded,
+        // One of the versions is unknown (e.g. not reported for the device), accept the driver
+        _ => true,
+    }
+}
+
+/// Map libwdi error to the platform-independent error code used by [`crate::RetryPolicy`]
+pub fn error_code(err: &wdi::Error) -> ErrorCode {
+    match err {
+        wdi::Error::Io => ErrorCode::Io,
+        wdi::Error::Access => ErrorCode::Access,
+        wdi::Error::NoDevice => ErrorCode::NoDevice,
+        wdi::Error::NotFound => ErrorCode::NotFound,
+        wdi::Error::Busy => ErrorCode::Busy,
+        wdi::Error::Timeout => ErrorCode::Timeout,
+        wdi::Error::Overflow => ErrorCode::Overflow,
+        wdi::Error::PendingInstallation => ErrorCode::PendingInstallation,
+        wdi::Error::Interrupted => ErrorCode::Interrupted,
+        wdi::Error::Resource => ErrorCode::Resource,
+        wdi::Error::NotSupported => ErrorCode::NotSupported,
+        wdi::Error::Exists => ErrorCode::Exists,
+        wdi::Error::UserCancel => ErrorCode::UserCancel,
+        wdi::Error::NeedsAdmin => ErrorCode::NeedsAdmin,
+        wdi::Error::Wow64 => ErrorCode::Wow64,
+        wdi::Error::InfSyntax => ErrorCode::InfSyntax,
+        wdi::Error::CatMissing => ErrorCode::CatMissing,
+        wdi::Error::Unsigned => ErrorCode::Unsigned,
+        _ => ErrorCode::Other,
+    }
+}
+
+/// Check if PnP manager reports that the device needs a restart to use its driver
+pub fn reboot_required(dev: &Device) -> bool {
+    let device_id = match &dev.device_id {
+        Some(id) => HSTRING::from(id.as_str()),
+        None => return false,
+    };
+    let mut devinst = 0;
+    let mut status = Cfg::CM_DEVNODE_STATUS_FLAGS(0);
+    let mut problem = Cfg::CM_PROB(0);
+    unsafe {
+        let id = PCWSTR::from_raw(device_id.as_ptr());
+        if Cfg::CM_Locate_DevNodeW(&mut devinst, id, Cfg::CM_LOCATE_DEVNODE_NORMAL) != Cfg::CR_SUCCESS {
+            log::warn!("Could not locate device node: {}", device_id);
+            return false;
+        }
+        if Cfg::CM_Get_DevNode_Status(&mut status, &mut problem, devinst, 0) != Cfg::CR_SUCCESS {
+            log::warn!("Could not get device node status: {}", device_id);
+            return false;
+        }
+    }
+    (status.0 & Cfg::DN_NEED_RESTART.0) != 0 || problem == Cfg::CM_PROB_NEED_RESTART
+}
+
+/// Check if Windows has file operations or servicing that will only complete after reboot
+pub fn reboot_pending() -> bool {
+    let session_manager = w!(r"SYSTEM\CurrentControlSet\Control\Session Manager");
+    let cbs = w!(r"SOFTWARE\Microsoft\Windows\CurrentVersion\Component Based Servicing\RebootPending");
+    registry_exists(session_manager, Some(w!("PendingFileRenameOperations")))
+        || registry_exists(cbs, None)
+}
+
+/// Check if a key (or its value) exists under `HKEY_LOCAL_MACHINE`
+fn registry_exists(subkey: PCWSTR, value: Option<PCWSTR>) -> bool {
+    let mut key = Registry::HKEY::default();
+    unsafe {
+        if Registry::RegOpenKeyExW(Registry::HKEY_LOCAL_MACHINE, subkey, 0, Registry::KEY_READ, &mut key) != ERROR_SUCCESS {
+            return false;
+        }
+        let exists = match value {
+            Some(value) => Registry::RegQueryValueExW(key, value, None, None, None, None) == ERROR_SUCCESS,
+            None => true,
+        };
+        let _ = Registry::RegCloseKey(key);
+        exists
+    }
+}
+
 fn install_winusb(dev: wdi::DeviceInfo<'_>, config: &InstallConfig) -> wdi::Result<()> {
     let opts = wdi::PrepareDriverOptions::new()
-        .driver_type(wdi::DriverType::WinUsb)
-        .vendor_name(&config.vendor).unwrap();
+        .driver_type(DRIVER_TYPE.into())
+        .vendor_name(&config.vendor).expect("Vendor name checked by InstallConfig::validate");
 
+    let _lock = LIBWDI_LOCK.lock().unwrap_or_else(|e| e.into_inner());
     let driver = opts.prepare_driver(dev, &config.driver_path, &config.inf_name)?;
     driver.install_driver()
 }
@@ -228,45 +342,141 @@ fn get_current_proc_windows() -> Vec<HWND> {
     }
 }
 
-#[allow(dead_code)]
-fn supported_drivers() {
-    use wdi::DriverType::*;
-    let types = [WinUsb, LibUsb0, LibUsbK, Cdc, User];
-    log::info!("Supported drivers");
-    for typ in types {
-        if let Some(info) = wdi::is_driver_supported(typ) {
-            log::info!("{:?}: supported, DriverInfo {{
-  dwSignature: {},
-  dwStrucVersion: {},
-  dwFileVersionMS: {},
-  dwFileVersionLS: {},
-  dwProductVersionMS: {},
-  dwProductVersionLS: {},
-  dwFileFlagsMask: {},
-  dwFileFlags: {},
-  dwFileOS: {},
-  dwFileType: {},
-  dwFileSubtype: {},
-  dwFileDateMS: {},
-  dwFileDateLS: {},
-}}",
-            typ,
-            info.0.dwSignature,
-            info.0.dwStrucVersion,
-            info.0.dwFileVersionMS,
-            info.0.dwFileVersionLS,
-            info.0.dwProductVersionMS,
-            info.0.dwProductVersionLS,
-            info.0.dwFileFlagsMask,
-            info.0.dwFileFlags,
-            info.0.dwFileOS,
-            info.0.dwFileType,
-            info.0.dwFileSubtype,
-            info.0.dwFileDateMS,
-            info.0.dwFileDateLS,
-        );
-        } else {
-            log::info!("{:?}: not supported", typ);
+impl From<DriverType> for wdi::DriverType {
+    fn from(typ: DriverType) -> Self {
+        match typ {
+            DriverType::WinUsb => wdi::DriverType::WinUsb,
+            DriverType::LibUsb0 => wdi::DriverType::LibUsb0,
+            DriverType::LibUsbK => wdi::DriverType::LibUsbK,
+            DriverType::Cdc => wdi::DriverType::Cdc,
+            DriverType::User => wdi::DriverType::User,
+        }
+    }
+}
+
+/// Check which driver types are supported by the embedded libwdi and their versions
+pub fn driver_support() -> Vec<DriverSupport> {
+    DriverType::ALL.iter()
+        .map(|&driver| match wdi::is_driver_supported(driver.into()) {
+            Some(info) => DriverSupport {
+                driver,
+                supported: true,
+                version: embedded_version(driver),
+                date: filetime_to_unix(info.0.dwFileDateMS, info.0.dwFileDateLS),
+            },
+            None => DriverSupport {
+                driver,
+                supported: false,
+                version: None,
+                date: None,
+            },
+        })
+        .collect()
+}
+
+/// Convert FILETIME (100ns intervals since 1601-01-01) to Unix timestamp, zero means no date
+fn filetime_to_unix(ms: u32, ls: u32) -> Option<u64> {
+    const INTERVALS_PER_SEC: u64 = 10_000_000;
+    const EPOCH_DIFF_SECS: u64 = 11_644_473_600;
+    let filetime = ((ms as u64) << 32) | ls as u64;
+    if filetime == 0 {
+        return None;
+    }
+    (filetime / INTERVALS_PER_SEC).checked_sub(EPOCH_DIFF_SECS)
+}
+
+/// List third-party driver packages from the driver store (`%SystemRoot%\INF\oem*.inf`)
+pub fn driver_store() -> io::Result<Vec<DriverPackage>> {
+    let root = env::var_os("SystemRoot")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(r"C:\Windows"));
+    let mut packages = Vec::new();
+    for entry in fs::read_dir(root.join("INF"))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let lower = name.to_lowercase();
+        if !(lower.starts_with("oem") && lower.ends_with(".inf")) {
+            continue;
+        }
+        match fs::read(entry.path()) {
+            Ok(data) => packages.push(parse_inf(name, &decode_inf(&data))),
+            Err(err) => log::warn!("Could not read {}: {}", name, err),
         }
     }
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(packages)
+}
+
+/// INF files are either UTF-16LE or UTF-8/ANSI
+fn decode_inf(data: &[u8]) -> String {
+    match data {
+        [0xff, 0xfe, rest @ ..] => {
+            let wide: Vec<u16> = rest.chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&wide)
+        },
+        [0xef, 0xbb, 0xbf, rest @ ..] => String::from_utf8_lossy(rest).into_owned(),
+        _ => String::from_utf8_lossy(data).into_owned(),
+    }
+}
+
+/// Extract package information from the `[Version]` section, resolving `%string%` tokens
+fn parse_inf(name: String, text: &str) -> DriverPackage {
+    let mut section = String::new();
+    let mut version = HashMap::new();
+    let mut strings = HashMap::new();
+    for line in text.lines() {
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].trim().to_lowercase();
+        } else if let Some((key, value)) = line.split_once('=') {
+            let key = key.trim().to_lowercase();
+            let value = value.trim().trim_matches('"').to_string();
+            match section.as_str() {
+                "version" => version.insert(key, value),
+                "strings" => strings.insert(key, value),
+                _ => None,
+            };
+        }
+    }
+    let resolve = |key: &str| {
+        version.get(key).map(|value| {
+            match value.strip_prefix('%').and_then(|v| v.strip_suffix('%')) {
+                Some(token) => strings.get(&token.to_lowercase()).unwrap_or(value).clone(),
+                None => value.clone(),
+            }
+        })
+    };
+    DriverPackage {
+        provider: resolve("provider"),
+        class: resolve("class"),
+        version: resolve("driverver"),
+        name,
+    }
+}
+
+/// Program Files directories (native and x86)
+///
+/// These are resolved by the shell from the system configuration, because the `ProgramFiles`
+/// environment variables can be set by the user (or by the server, for the client).
+pub(crate) fn program_files_dirs() -> Vec<String> {
+    [Shell::FOLDERID_ProgramFiles, Shell::FOLDERID_ProgramFilesX86].iter()
+        .filter_map(|id| match known_folder(id) {
+            Ok(path) => Some(path),
+            Err(err) => {
+                log::warn!("Could not get known folder {:?}: {}", id, err);
+                None
+            },
+        })
+        .collect()
+}
+
+fn known_folder(id: &GUID) -> io::Result<String> {
+    let path = unsafe { Shell::SHGetKnownFolderPath(id, Shell::KF_FLAG_DEFAULT, HANDLE(0)) }?;
+    let result = unsafe { path.to_string() };
+    unsafe {
+        Com::CoTaskMemFree(Some(path.0 as *const c_void));
+    }
+    result.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
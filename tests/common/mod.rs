@@ -0,0 +1,100 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+use winusb_installer::elevate::Unelevated;
+use winusb_installer::{Client, DeviceMatcher, Operation, SecurityPolicy, Server};
+
+/// Root directory passed to the spawned client, the server cannot change it over IPC
+pub const ROOT_ENV_VAR: &str = "WINUSB_INSTALLER_TEST_ROOT";
+
+/// Environment variable that the server can set in the client besides `RUST_LOG`
+pub const TEST_ENV_VAR: &str = "WINUSB_INSTALLER_TEST_VAR";
+
+/// Vendor ID of the only devices that the client is allowed to install drivers for
+pub const ALLOWED_VID: u16 = 0x1209;
+
+/// Read an environment variable of the client
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadEnv(pub String);
+
+impl Operation for ReadEnv {
+    const NAME: &'static str = "read-env";
+    type Output = Option<String>;
+}
+
+/// Serve the server when the test executable was spawned as the client
+///
+/// Returns false when running as the test itself.
+pub async fn run_client() -> bool {
+    let root = match env::var_os(ROOT_ENV_VAR) {
+        Some(root) => root,
+        None => return false,
+    };
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    let mut client = Client::from_args(&args).expect("Invalid client arguments");
+    client.root(root)
+        .security_policy(SecurityPolicy {
+            allowed_devices: Some(vec![DeviceMatcher::vid(ALLOWED_VID)]),
+            allowed_env_vars: Some(vec!["RUST_LOG".to_string(), TEST_ENV_VAR.to_string()]),
+            ..SecurityPolicy::default()
+        })
+        .register_operation(|ReadEnv(name)| Ok::<_, String>(env::var(name).ok()));
+    client.serve().await.expect("Client failed");
+    true
+}
+
+/// Server spawning the test executable as an unelevated client working in `root`
+pub fn server(name: &str, root: &FakeRoot) -> Server {
+    env::set_var(ROOT_ENV_VAR, root.path());
+    let mut server = Server::new();
+    server.elevator(Unelevated)
+        .pipe_id(&format!("winusb-installer-{}-{}", name, std::process::id()))
+        .root(root.path());
+    server
+}
+
+/// Temporary directory used as the root for sysfs and udev rules, removed when dropped
+pub struct FakeRoot(PathBuf);
+
+impl FakeRoot {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("winusb-installer-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(path.join("sys/bus/usb/devices")).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Add a USB device with a single interface to sysfs, as `/sys/bus/usb/devices/<name>`
+    pub fn add_device(&self, name: &str, vid: u16, pid: u16, product: &str) {
+        let dir = self.0.join("sys/bus/usb/devices").join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("idVendor"), format!("{:04x}\n", vid)).unwrap();
+        fs::write(dir.join("idProduct"), format!("{:04x}\n", pid)).unwrap();
+        fs::write(dir.join("product"), format!("{}\n", product)).unwrap();
+        fs::write(dir.join("bNumInterfaces"), " 1\n").unwrap();
+        fs::create_dir_all(self.0.join("sys/bus/usb/devices").join(format!("{}:1.0", name))).unwrap();
+    }
+
+    /// Content of the rules file generated for given .inf name
+    pub fn rules(&self, inf_name: &str) -> Option<String> {
+        let stem = inf_name.trim_end_matches(".inf");
+        fs::read_to_string(self.0.join("etc/udev/rules.d").join(format!("70-{}.rules", stem))).ok()
+    }
+}
+
+impl Drop for FakeRoot {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
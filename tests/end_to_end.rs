@@ -0,0 +1,179 @@
+//! Whole installation flow on Linux: spawn → connect → install → exit
+//!
+//! The client is spawned using [`Unelevated`] and works on a fake sysfs tree and udev rules
+//! in a temporary directory. The test executable is also the client executable, so it runs
+//! without the libtest harness.
+
+#[cfg(target_os = "linux")]
+mod common;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::time::Duration;
+
+    use winusb_installer::{Error, ErrorCode, InstallConfig, InstallOutcome, PolicyViolation, Progress, Server, WatchEvent};
+
+    use crate::common::{self, FakeRoot};
+
+    const INF_NAME: &str = "test-device.inf";
+
+    pub async fn main() {
+        env_logger::builder().is_test(true).try_init().ok();
+
+        // Spawned by the server below
+        if common::run_client().await {
+            return;
+        }
+
+        let root = FakeRoot::new("end-to-end");
+        root.add_device("1-2", 0x1209, 0x0001, "Test device");
+        root.add_device("1-3", 0x1234, 0x5678, "Other device");
+        let mut server = common::server("end-to-end", &root);
+
+        install(&mut server, &root).await;
+        install_again(&mut server).await;
+        query(&mut server).await;
+        install_missing(&mut server).await;
+        install_rejected(&mut server, &root).await;
+        environment(&root).await;
+        rejected_environment(&root).await;
+        install_on_arrival().await;
+        println!("test end_to_end ... ok");
+    }
+
+    fn config() -> InstallConfig {
+        InstallConfig::new("Test Vendor", INF_NAME)
+    }
+
+    async fn install(server: &mut Server, root: &FakeRoot) {
+        let devices: Vec<_> = server.visible_devices().unwrap()
+            .into_iter()
+            .filter(|dev| dev.vid == 0x1209)
+            .collect();
+        assert_eq!(devices.len(), 1);
+
+        let mut progress = Vec::new();
+        let report = server.install(config(), &devices, |p| progress.push(p)).await.unwrap();
+        assert!(matches!(progress.first(), Some(Progress::Started)), "{:?}", progress);
+        assert_eq!(report.devices.len(), 1);
+        assert_eq!(report.devices[0].outcome, InstallOutcome::Installed);
+        assert!(report.all_installed());
+
+        let rules = root.rules(INF_NAME).expect("Rules file not created");
+        assert!(rules.contains(r#"ATTRS{idVendor}=="1209", ATTRS{idProduct}=="0001""#), "{}", rules);
+        assert!(!rules.contains("5678"), "{}", rules);
+    }
+
+    /// Rules are already present, so the device gets skipped by the default policy
+    async fn install_again(server: &mut Server) {
+        let devices: Vec<_> = server.visible_devices().unwrap()
+            .into_iter()
+            .filter(|dev| dev.vid == 0x1209)
+            .collect();
+        let report = server.install(config(), &devices, |_| {}).await.unwrap();
+        assert_eq!(report.devices.len(), 1);
+        assert_eq!(report.devices[0].outcome, InstallOutcome::Skipped);
+    }
+
+    /// Requested devices that the client cannot find are reported as failed
+    async fn install_missing(server: &mut Server) {
+        let mut missing = server.visible_devices().unwrap()
+            .into_iter()
+            .find(|dev| dev.vid == 0x1209)
+            .unwrap();
+        missing.pid = 0x0002;
+        missing.device_id = Some("1-9".to_string());
+        let mut progress = Vec::new();
+        let report = server.install(config(), &[missing.clone()], |p| progress.push(p)).await.unwrap();
+        assert_eq!(report.devices.len(), 1);
+        assert_eq!(report.devices[0].device, missing);
+        assert!(matches!(&report.devices[0].outcome, InstallOutcome::Failed(err) if err.code == ErrorCode::NotFound));
+        assert!(!report.all_installed());
+        assert!(progress.iter().any(|p| matches!(p, Progress::Device(dev) if dev.device == missing)), "{:?}", progress);
+    }
+
+    /// Devices not allowed by the security policy of the client fail the whole request
+    async fn install_rejected(server: &mut Server, root: &FakeRoot) {
+        let devices = server.visible_devices().unwrap();
+        assert!(devices.iter().any(|dev| dev.vid != common::ALLOWED_VID));
+        let config = InstallConfig::new("Test Vendor", "rejected.inf");
+        match server.install(config, &devices, |_| {}).await {
+            Err(Error::PolicyViolation(PolicyViolation::DeviceNotAllowed { vid, pid })) => {
+                assert_eq!((vid, pid), (0x1234, 0x5678));
+            },
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(root.rules("rejected.inf"), None);
+    }
+
+    async fn query(server: &mut Server) {
+        let status = server.query().await.unwrap();
+        assert_eq!(status.devices.len(), 2);
+        assert_eq!(status.driver_store.len(), 1);
+        assert_eq!(status.driver_store[0].name, "70-test-device.rules");
+        assert_eq!(status.driver_store[0].provider.as_deref(), Some("Test Vendor"));
+    }
+
+    /// Only the device plugged in while waiting gets installed, not the ones already present
+    async fn install_on_arrival() {
+        let root = FakeRoot::new("arrival");
+        root.add_device("1-2", 0x1209, 0x0001, "Present device");
+        let mut server = common::server("arrival", &root);
+        server.poll_interval(Duration::from_millis(20))
+            .debounce(Duration::from_millis(60));
+
+        let mut events = Vec::new();
+        let wait = server.install_on_arrival(config(), |dev| dev.vid == 0x1209, Duration::from_millis(300), |e| events.push(e));
+        match wait.await {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert!(events.is_empty(), "{:?}", events);
+
+        let wait = server.install_on_arrival(config(), |dev| dev.vid == 0x1209, Duration::from_secs(10), |e| events.push(e));
+        let plug = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            root.add_device("1-5", 0x1234, 0x0001, "Other device");
+            root.add_device("1-4", 0x1209, 0x0002, "Bootloader");
+        };
+        let (report, ()) = tokio::join!(wait, plug);
+        let report = report.unwrap();
+        assert_eq!(report.devices.len(), 1);
+        assert_eq!(report.devices[0].device.pid, 0x0002);
+        assert_eq!(report.devices[0].outcome, InstallOutcome::Installed);
+        assert!(matches!(&events[0], WatchEvent::Arrived(dev) if dev.pid == 0x0002), "{:?}", events);
+        assert!(events[1..].iter().all(|e| matches!(e, WatchEvent::Install(_))), "{:?}", events);
+
+        let rules = root.rules(INF_NAME).expect("Rules file not created");
+        assert!(rules.contains(r#"ATTRS{idProduct}=="0002""#), "{}", rules);
+        assert!(!rules.contains(r#"ATTRS{idProduct}=="0001""#), "{}", rules);
+    }
+
+    /// Variables allowed by the security policy of the client are applied
+    async fn environment(root: &FakeRoot) {
+        let mut server = common::server("end-to-end-env", root);
+        server.client_env(common::TEST_ENV_VAR, "a=b c");
+        let value = server.run_operation(common::ReadEnv(common::TEST_ENV_VAR.to_string())).await.unwrap();
+        assert_eq!(value.as_deref(), Some("a=b c"));
+    }
+
+    /// Variables that are not allowed by the security policy of the client fail the connection
+    async fn rejected_environment(root: &FakeRoot) {
+        let mut server = common::server("end-to-end-rejected-env", root);
+        server.client_env("PATH", "/tmp");
+        match server.query().await {
+            Err(Error::PolicyViolation(PolicyViolation::EnvVarNotAllowed(name))) => assert_eq!(name, "PATH"),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    linux::main().await;
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}
@@ -0,0 +1,87 @@
+//! Record an installation and replay both sides of the recorded conversation
+//!
+//! Like `end_to_end`, the test executable is also the client executable, so it runs without the
+//! libtest harness.
+
+#[cfg(target_os = "linux")]
+mod common;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use winusb_installer::ipc::{self, Direction, Record};
+    use winusb_installer::replay::{replay_client, replay_server};
+    use winusb_installer::{Client, DeviceReport, InstallConfig, InstallOutcome};
+
+    use crate::common::{self, FakeRoot};
+
+    const INF_NAME: &str = "test-device.inf";
+
+    pub async fn main() {
+        env_logger::builder().is_test(true).try_init().ok();
+
+        // Spawned by the server below
+        if common::run_client().await {
+            return;
+        }
+
+        let root = FakeRoot::new("replay");
+        root.add_device("1-2", 0x1209, 0x0001, "Test device");
+        let recording = root.path().join("recording.jsonl");
+
+        let mut server = common::server("replay", &root);
+        server.record_ipc(&recording);
+        let devices = server.visible_devices().unwrap();
+        let config = InstallConfig::new("Test Vendor", INF_NAME);
+        let report = server.install(config, &devices, |_| {}).await.unwrap();
+        assert_eq!(report.devices.len(), 1);
+        assert_eq!(report.devices[0].outcome, InstallOutcome::Installed);
+        drop(server);
+
+        let records = ipc::read_recording(&recording).unwrap();
+        assert!(!records.is_empty());
+        server_side(&records, &report.devices).await;
+        client_side(&records).await;
+        println!("test replay ... ok");
+    }
+
+    /// Recorded responses produce the same reports as the live installation
+    async fn server_side(records: &[Record], expected: &[DeviceReport]) {
+        let reports = replay_server(records, false, |_| {}).await.unwrap();
+        assert_eq!(reports, expected);
+    }
+
+    /// Recorded requests install the rules again under a fresh root
+    async fn client_side(records: &[Record]) {
+        let root = FakeRoot::new("replay-client");
+        root.add_device("1-2", 0x1209, 0x0001, "Test device");
+        let mut client = Client::new(String::new());
+        client.root(root.path());
+
+        let replayed = replay_client(&client, records, false).await.unwrap();
+        let rules = root.rules(INF_NAME).expect("Rules file not created");
+        assert!(rules.contains(r#"ATTRS{idVendor}=="1209", ATTRS{idProduct}=="0001""#), "{}", rules);
+
+        // Every request gets a successful response again
+        let recorded = responses(records).filter(|ok| *ok).count();
+        let results: Vec<_> = responses(&replayed).collect();
+        assert_eq!(results.len(), recorded, "{:?}", replayed);
+        assert!(results.iter().all(|ok| *ok), "{:?}", replayed);
+    }
+
+    /// Whether each response sent by the client was successful
+    fn responses(records: &[Record]) -> impl Iterator<Item = bool> + '_ {
+        records.iter()
+            .filter(|record| record.direction == Direction::ClientToServer)
+            .filter_map(|record| record.message.get("Response"))
+            .map(|response| response["result"].get("Ok").is_some())
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    linux::main().await;
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}